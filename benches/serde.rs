@@ -0,0 +1,77 @@
+//! Compares the bulk (de)serialization helpers against the per-byte loops
+//! they replaced.
+//!
+//! Run with `cargo bench --bench serde`.
+
+use std::io::Cursor;
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use thorkv::util::serde::{self, CURRENT_VERSION};
+
+const VALUE_SIZES: [usize; 3] = [16, 1024, 64 * 1024];
+
+// The per-byte loops serialize_u8_vec and deserialize_u8_vec used before
+fn serialize_per_byte(res: &mut Vec<u8>, data: &[u8]) {
+    serde::serialize_usize(res, data.len());
+    for d in data {
+        res.write_u8(*d).unwrap();
+    }
+}
+
+fn deserialize_per_byte(rdr: &mut Cursor<&[u8]>) -> Vec<u8> {
+    let mut res = Vec::new();
+    let size = serde::deserialize_usize(rdr).unwrap();
+    for _ in 0..size {
+        res.push(rdr.read_u8().unwrap());
+    }
+    res
+}
+
+fn bench_serialize(c: &mut Criterion) {
+    for size in VALUE_SIZES.iter() {
+        let data = vec![0xabu8; *size];
+        c.bench_function(&format!("serialize per-byte {}", size), |b| {
+            b.iter(|| {
+                let mut res = Vec::new();
+                serialize_per_byte(&mut res, black_box(&data));
+                res
+            })
+        });
+        c.bench_function(&format!("serialize bulk {}", size), |b| {
+            b.iter(|| {
+                let mut res = Vec::new();
+                serde::serialize_u8_vec(&mut res, black_box(&data));
+                res
+            })
+        });
+    }
+}
+
+fn bench_deserialize(c: &mut Criterion) {
+    for size in VALUE_SIZES.iter() {
+        let mut buf = Vec::new();
+        serde::serialize_u8_vec(&mut buf, &vec![0xabu8; *size]);
+        c.bench_function(&format!("deserialize per-byte {}", size), |b| {
+            b.iter(|| deserialize_per_byte(&mut Cursor::new(black_box(&buf))))
+        });
+        c.bench_function(&format!("deserialize bulk {}", size), |b| {
+            b.iter(|| {
+                let mut rdr = Cursor::new(black_box(&buf[..]));
+                serde::deserialize_u8_vec(&mut rdr, CURRENT_VERSION).unwrap()
+            })
+        });
+        c.bench_function(&format!("deserialize borrowed {}", size), |b| {
+            b.iter(|| {
+                let mut rdr = Cursor::new(black_box(&buf[..]));
+                let version = CURRENT_VERSION;
+                let data = serde::deserialize_u8_slice(&mut rdr, version);
+                data.unwrap().len()
+            })
+        });
+    }
+}
+
+criterion_group!(benches, bench_serialize, bench_deserialize);
+criterion_main!(benches);
@@ -0,0 +1,82 @@
+//! Sequential and random reads and writes against the lock-free map storage
+//! and the DB. See thorkv-bench for multi-threaded YCSB workloads.
+//!
+//! Run with `cargo bench --bench storage`.
+
+use criterion::{
+    black_box, criterion_group, criterion_main, BenchmarkId, Criterion,
+};
+
+use thorkv::db::DB;
+use thorkv::storage::KeyValueStorage;
+use thorkv::storage::lfmap::LFMapStorage;
+
+const RECORDS: u64 = 100_000;
+const VALUE: [u8; 100] = [0xab; 100];
+
+fn key(index: u64) -> Vec<u8> {
+    format!("user{:020}", index).into_bytes()
+}
+
+// The order keys are accessed in
+fn access_order(pattern: &str) -> impl FnMut() -> u64 {
+    let random = pattern == "random";
+    let mut state: u64 = 0;
+    move || {
+        // Steps of an odd multiple of the golden ratio visit every key
+        state = state.wrapping_add(if random { 0x9e37_79b9 } else { 1 });
+        state % RECORDS
+    }
+}
+
+fn bench_storage(c: &mut Criterion) {
+    let storage = LFMapStorage::new();
+    for i in 0..RECORDS {
+        storage.put(&key(i), &VALUE);
+    }
+    let mut group = c.benchmark_group("lfmap");
+    for pattern in ["sequential", "random"].iter() {
+        group.bench_function(BenchmarkId::new("get", pattern), |b| {
+            let mut next = access_order(pattern);
+            b.iter(|| storage.get(black_box(&key(next()))))
+        });
+        group.bench_function(BenchmarkId::new("put", pattern), |b| {
+            let mut next = access_order(pattern);
+            b.iter(|| storage.put(black_box(&key(next())), &VALUE))
+        });
+    }
+    group.finish();
+}
+
+fn bench_db(c: &mut Criterion) {
+    let dir = std::env::temp_dir()
+        .join(format!("thorkv-bench-storage-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let db = DB::open(&dir).unwrap();
+    let records = (0..RECORDS).map(|i| Ok((key(i), VALUE)));
+    db.ingest(records).unwrap();
+    
+    let mut group = c.benchmark_group("db");
+    for pattern in ["sequential", "random"].iter() {
+        group.bench_function(BenchmarkId::new("get", pattern), |b| {
+            let mut next = access_order(pattern);
+            b.iter(|| db.get(black_box(&key(next()))).unwrap())
+        });
+    }
+    // Every put waits for an fsync
+    group.sample_size(20);
+    for pattern in ["sequential", "random"].iter() {
+        group.bench_function(BenchmarkId::new("put", pattern), |b| {
+            let mut next = access_order(pattern);
+            b.iter(|| db.put(black_box(&key(next())), VALUE).unwrap())
+        });
+    }
+    group.finish();
+    
+    db.close().unwrap();
+    drop(db);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+criterion_group!(benches, bench_storage, bench_db);
+criterion_main!(benches);
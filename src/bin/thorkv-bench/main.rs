@@ -0,0 +1,406 @@
+//! Drives ThorKV with the YCSB core workloads and reports throughput and
+//! latency percentiles.
+//!
+//! A run loads the records, then the client threads share the operations
+//! between them, each picking operations according to the workload's mix.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use thorkv::db::DB;
+use thorkv::storage::KeyValueStorage;
+use thorkv::storage::lfmap::LFMapStorage;
+use thorkv::types::Error;
+
+mod report;
+mod workload;
+
+use report::Report;
+use workload::{Distribution, KeyChooser, Operation, Rng, Workload};
+
+const USAGE: &str = "\
+Usage: thorkv-bench [options]
+
+Options:
+    --workload a|b|c|d|e|f  YCSB core workload (default a):
+                              a  50% read, 50% update
+                              b  95% read, 5% update
+                              c  100% read
+                              d  95% read, 5% insert, latest keys first
+                              e  95% scan, 5% insert
+                              f  50% read, 50% read-modify-write
+    --target db|lfmap       Drive the DB (default), where every write is a
+                            durable transaction, or the lock-free map
+                            storage backend on its own
+    --records <n>           Records loaded before the run (default 100000)
+    --operations <n>        Operations in the run, shared by all threads
+                            (default 100000)
+    --threads <n>           Client threads (default 1)
+    --key-size <bytes>      Default 24
+    --value-size <bytes>    Default 100
+    --distribution uniform|zipfian|latest|sequential
+                            Override the workload's key distribution
+    --theta <f>             Zipfian skew, between 0 and 1 (default 0.99)
+    --scan-length <n>       Longest scan (default 100). There are no range
+                            scans, a scan reads consecutive keys one by one
+    --checkpoint idle|busy  With idle (default) only the periodic
+                            checkpointer runs, with busy checkpoints are
+                            taken back to back during the run. db only
+    --dir <path>            Database directory, which must not exist yet
+                            (default: a temporary directory, removed after
+                            the run)
+";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TargetKind {
+    Db,
+    LFMap,
+}
+
+struct Config {
+    workload: Workload,
+    target: TargetKind,
+    records: u64,
+    operations: u64,
+    threads: u64,
+    key_size: usize,
+    value_size: usize,
+    distribution: Distribution,
+    theta: f64,
+    scan_length: u64,
+    busy_checkpoints: bool,
+    dir: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            workload: Workload::A,
+            target: TargetKind::Db,
+            records: 100_000,
+            operations: 100_000,
+            threads: 1,
+            key_size: 24,
+            value_size: 100,
+            distribution: Workload::A.distribution(),
+            theta: 0.99,
+            scan_length: 100,
+            busy_checkpoints: false,
+            dir: None,
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match parse_args(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("thorkv-bench: {}", err);
+            eprint!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(err) = run(&config) {
+        eprintln!("thorkv-bench: {}", err);
+        process::exit(1);
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Config, String> {
+    let mut config = Config::default();
+    let mut distribution = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().map(String::as_str)
+                .ok_or_else(|| format!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "--workload" => {
+                let name = value()?;
+                config.workload = Workload::from_name(name)
+                    .ok_or_else(|| format!("Unknown workload {}", name))?;
+            }
+            "--target" => {
+                config.target = match value()? {
+                    "db" => TargetKind::Db,
+                    "lfmap" => TargetKind::LFMap,
+                    target => return Err(format!("Unknown target {}", target)),
+                };
+            }
+            "--records" => config.records = parse(arg, value()?)?,
+            "--operations" => config.operations = parse(arg, value()?)?,
+            "--threads" => config.threads = parse(arg, value()?)?,
+            "--key-size" => config.key_size = parse(arg, value()?)?,
+            "--value-size" => config.value_size = parse(arg, value()?)?,
+            "--distribution" => {
+                let name = value()?;
+                distribution = Some(
+                    Distribution::from_name(name).ok_or_else(|| {
+                        format!("Unknown distribution {}", name)
+                    })?
+                );
+            }
+            "--theta" => config.theta = parse(arg, value()?)?,
+            "--scan-length" => config.scan_length = parse(arg, value()?)?,
+            "--checkpoint" => {
+                config.busy_checkpoints = match value()? {
+                    "idle" => false,
+                    "busy" => true,
+                    mode => {
+                        return Err(format!("Unknown checkpoint mode {}", mode))
+                    }
+                };
+            }
+            "--dir" => config.dir = Some(PathBuf::from(value()?)),
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+    config.distribution =
+        distribution.unwrap_or_else(|| config.workload.distribution());
+    
+    if config.records == 0 || config.threads == 0 || config.scan_length == 0 {
+        return Err(String::from(
+            "--records, --threads and --scan-length must be at least 1"
+        ));
+    }
+    if !(config.theta > 0.0 && config.theta < 1.0) {
+        return Err(String::from("--theta must be between 0 and 1"));
+    }
+    if config.busy_checkpoints && config.target != TargetKind::Db {
+        return Err(String::from("--checkpoint busy needs --target db"));
+    }
+    Ok(config)
+}
+
+fn parse<T: std::str::FromStr>(option: &str, value: &str)
+    -> Result<T, String>
+{
+    value.parse()
+        .map_err(|_| format!("Invalid value {} for {}", value, option))
+}
+
+/// What the operations run against
+trait Target: Sync {
+    fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+    fn write(&self, key: &[u8], value: &[u8]) -> Result<(), Error>;
+    fn load(&self, records: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>)
+        -> Result<(), Error>;
+    // Take a checkpoint, returns false if the target has none
+    fn checkpoint(&self) -> Result<bool, Error> {
+        Ok(false)
+    }
+}
+
+impl Target for DB {
+    fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.get(key)
+    }
+    
+    fn write(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.put(key, value)
+    }
+    
+    fn load(&self, records: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>)
+        -> Result<(), Error>
+    {
+        self.ingest(records.map(Ok))?;
+        Ok(())
+    }
+    
+    fn checkpoint(&self) -> Result<bool, Error> {
+        DB::checkpoint(self)?;
+        Ok(true)
+    }
+}
+
+impl Target for LFMapStorage {
+    fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.get(key))
+    }
+    
+    fn write(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.put(key, value);
+        Ok(())
+    }
+    
+    fn load(&self, records: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>)
+        -> Result<(), Error>
+    {
+        for (key, value) in records {
+            self.put(&key, &value);
+        }
+        Ok(())
+    }
+}
+
+fn run(config: &Config) -> Result<(), Error> {
+    match config.target {
+        TargetKind::Db => {
+            let (dir, temporary) = match &config.dir {
+                Some(dir) => (dir.clone(), false),
+                None => {
+                    let name = format!("thorkv-bench-{}", process::id());
+                    (env::temp_dir().join(name), true)
+                }
+            };
+            if dir.exists() {
+                return Err(Error::new(
+                    format!("{} already exists", dir.display())
+                ));
+            }
+            let db = DB::open(&dir)?;
+            let res = bench(config, &*db);
+            let closed = db.close();
+            if temporary {
+                drop(db);
+                fs::remove_dir_all(&dir)?;
+            }
+            res.and(closed)
+        }
+        TargetKind::LFMap => bench(config, &LFMapStorage::new()),
+    }
+}
+
+fn bench(config: &Config, target: &dyn Target) -> Result<(), Error> {
+    println!(
+        "workload {:?}, {:?} distribution, {} records, {} operations, \
+         {} threads, {} byte keys, {} byte values",
+        config.workload, config.distribution, config.records,
+        config.operations, config.threads, config.key_size, config.value_size
+    );
+    
+    let start = Instant::now();
+    let mut rng = Rng::new(0);
+    let mut records = (0..config.records).map(|i| {
+        let mut value = vec![0; config.value_size];
+        rng.fill(&mut value);
+        (workload::key(i, config.key_size), value)
+    });
+    target.load(&mut records)?;
+    println!("Loaded in {:.2}s", start.elapsed().as_secs_f64());
+    
+    let chooser = KeyChooser::new(
+        config.distribution,
+        config.records,
+        config.theta,
+    );
+    // Number of keys inserted so far, the next insert takes this index
+    let inserted = AtomicU64::new(config.records);
+    let done = AtomicBool::new(false);
+    let checkpoints = AtomicU64::new(0);
+    
+    let start = Instant::now();
+    let res = thread::scope(|scope| {
+        let checkpointer = if config.busy_checkpoints {
+            Some(scope.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    target.checkpoint()?;
+                    checkpoints.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(())
+            }))
+        } else {
+            None
+        };
+        let clients: Vec<_> = (0..config.threads)
+            .map(|i| {
+                let share = config.operations / config.threads
+                    + (i < config.operations % config.threads) as u64;
+                let client = Client {
+                    config,
+                    target,
+                    chooser: &chooser,
+                    inserted: &inserted,
+                };
+                scope.spawn(move || client.run(i + 1, share))
+            })
+            .collect();
+        
+        let mut report = Report::new();
+        let mut res = Ok(());
+        for client in clients {
+            match client.join().unwrap() {
+                Ok(client_report) => report.merge(client_report),
+                Err(err) => res = Err(err),
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+        if let Some(checkpointer) = checkpointer {
+            res = res.and(checkpointer.join().unwrap());
+        }
+        res.map(|_| report)
+    });
+    let elapsed = start.elapsed();
+    
+    res?.print(elapsed);
+    if config.busy_checkpoints {
+        println!("{} checkpoints", checkpoints.load(Ordering::Relaxed));
+    }
+    Ok(())
+}
+
+// One client thread
+struct Client<'a> {
+    config: &'a Config,
+    target: &'a dyn Target,
+    chooser: &'a KeyChooser,
+    inserted: &'a AtomicU64,
+}
+
+impl Client<'_> {
+    fn run(&self, seed: u64, operations: u64) -> Result<Report, Error> {
+        let mut rng = Rng::new(seed);
+        let mut report = Report::new();
+        let mut value = vec![0; self.config.value_size];
+        for _ in 0..operations {
+            let op = self.config.workload.next_operation(&mut rng);
+            let start = Instant::now();
+            match op {
+                Operation::Read => {
+                    self.target.read(&self.next_key(&mut rng))?;
+                }
+                Operation::Update => {
+                    rng.fill(&mut value);
+                    self.target.write(&self.next_key(&mut rng), &value)?;
+                }
+                Operation::Insert => {
+                    // Readers may pick the key before it's written and miss
+                    let index = self.inserted.fetch_add(1, Ordering::Relaxed);
+                    let key = workload::key(index, self.config.key_size);
+                    rng.fill(&mut value);
+                    self.target.write(&key, &value)?;
+                }
+                Operation::Scan => {
+                    let inserted = self.inserted.load(Ordering::Relaxed);
+                    let first = self.chooser.next(&mut rng, inserted);
+                    let len = rng.below(self.config.scan_length) + 1;
+                    for index in first..(first + len).min(inserted) {
+                        let key = workload::key(index, self.config.key_size);
+                        self.target.read(&key)?;
+                    }
+                }
+                Operation::ReadModifyWrite => {
+                    let key = self.next_key(&mut rng);
+                    self.target.read(&key)?;
+                    rng.fill(&mut value);
+                    self.target.write(&key, &value)?;
+                }
+            }
+            report.record(op, start.elapsed());
+        }
+        Ok(report)
+    }
+    
+    fn next_key(&self, rng: &mut Rng) -> Vec<u8> {
+        let inserted = self.inserted.load(Ordering::Relaxed);
+        let index = self.chooser.next(rng, inserted);
+        workload::key(index, self.config.key_size)
+    }
+}
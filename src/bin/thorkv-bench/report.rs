@@ -0,0 +1,89 @@
+//! Latency samples and the summary printed after a run.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::workload::Operation;
+
+/// Latency of every operation, by kind. Each client thread fills its own and
+/// they are merged at the end.
+#[derive(Default)]
+pub struct Report {
+    // Nanoseconds
+    latencies: BTreeMap<Operation, Vec<u64>>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    pub fn record(&mut self, op: Operation, latency: Duration) {
+        let nanos = latency.as_nanos() as u64;
+        self.latencies.entry(op).or_default().push(nanos);
+    }
+    
+    pub fn merge(&mut self, other: Report) {
+        for (op, latencies) in other.latencies {
+            self.latencies.entry(op).or_default().extend(latencies);
+        }
+    }
+    
+    /// Print throughput and p50/p99/p999 latency per operation and overall
+    pub fn print(mut self, elapsed: Duration) {
+        let mut all: Vec<u64> = Vec::new();
+        for latencies in self.latencies.values_mut() {
+            latencies.sort_unstable();
+            all.extend_from_slice(latencies);
+        }
+        all.sort_unstable();
+        
+        let secs = elapsed.as_secs_f64();
+        println!(
+            "{} operations in {:.2}s, {:.0} ops/s",
+            all.len(), secs, all.len() as f64 / secs
+        );
+        println!(
+            "{:<18} {:>10} {:>10} {:>10} {:>10}",
+            "operation", "count", "p50 us", "p99 us", "p999 us"
+        );
+        for (op, latencies) in &self.latencies {
+            print_row(op.name(), latencies);
+        }
+        print_row("all", &all);
+    }
+}
+
+fn print_row(name: &str, sorted: &[u64]) {
+    let micros = |p| percentile(sorted, p) as f64 / 1000.0;
+    println!(
+        "{:<18} {:>10} {:>10.1} {:>10.1} {:>10.1}",
+        name, sorted.len(), micros(50.0), micros(99.0), micros(99.9)
+    );
+}
+
+/// Nearest-rank percentile of sorted samples, 0 if there are none
+pub fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    // The epsilon keeps e.g. 99.9% of 1000 from rounding up to 1000
+    let rank = (p / 100.0 * sorted.len() as f64 - 1e-9).ceil() as usize;
+    sorted[rank.max(1) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn nearest_rank_percentiles() {
+        let samples: Vec<u64> = (1..=1000).collect();
+        assert_eq!(percentile(&samples, 50.0), 500);
+        assert_eq!(percentile(&samples, 99.0), 990);
+        assert_eq!(percentile(&samples, 99.9), 999);
+        assert_eq!(percentile(&samples, 100.0), 1000);
+        assert_eq!(percentile(&[7], 50.0), 7);
+        assert_eq!(percentile(&[], 50.0), 0);
+    }
+}
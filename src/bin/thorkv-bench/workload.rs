@@ -0,0 +1,283 @@
+//! YCSB core workloads: the operation mix and how keys are chosen.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Operations a workload issues
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operation {
+    Read,
+    Update,
+    Insert,
+    Scan,
+    ReadModifyWrite,
+}
+
+impl Operation {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Update => "update",
+            Self::Insert => "insert",
+            Self::Scan => "scan",
+            Self::ReadModifyWrite => "read-modify-write",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Distribution {
+    Uniform,
+    /// Popular keys are scattered over the key space
+    Zipfian,
+    /// Zipfian over recency, the most recently inserted keys are the most
+    /// popular
+    Latest,
+    /// Walk the keys in order, wrapping around
+    Sequential,
+}
+
+impl Distribution {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "uniform" => Some(Self::Uniform),
+            "zipfian" => Some(Self::Zipfian),
+            "latest" => Some(Self::Latest),
+            "sequential" => Some(Self::Sequential),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Workload {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+}
+
+impl Workload {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "a" | "A" => Some(Self::A),
+            "b" | "B" => Some(Self::B),
+            "c" | "C" => Some(Self::C),
+            "d" | "D" => Some(Self::D),
+            "e" | "E" => Some(Self::E),
+            "f" | "F" => Some(Self::F),
+            _ => None,
+        }
+    }
+    
+    /// Percentage of each operation, adding up to 100
+    pub fn mix(self) -> &'static [(Operation, u64)] {
+        use Operation::*;
+        match self {
+            Self::A => &[(Read, 50), (Update, 50)],
+            Self::B => &[(Read, 95), (Update, 5)],
+            Self::C => &[(Read, 100)],
+            Self::D => &[(Read, 95), (Insert, 5)],
+            Self::E => &[(Scan, 95), (Insert, 5)],
+            Self::F => &[(Read, 50), (ReadModifyWrite, 50)],
+        }
+    }
+    
+    pub fn distribution(self) -> Distribution {
+        match self {
+            Self::D => Distribution::Latest,
+            _ => Distribution::Zipfian,
+        }
+    }
+    
+    pub fn next_operation(self, rng: &mut Rng) -> Operation {
+        let mut r = rng.below(100);
+        for (op, percent) in self.mix() {
+            if r < *percent {
+                return *op;
+            }
+            r -= percent;
+        }
+        unreachable!("the mix adds up to 100")
+    }
+}
+
+/// The key stored under index, zero padded to size bytes so that keys sort
+/// in index order
+pub fn key(index: u64, size: usize) -> Vec<u8> {
+    let width = size.saturating_sub(4);
+    format!("user{:0width$}", index, width = width).into_bytes()
+}
+
+/// SplitMix64, fast and good enough to pick keys and fill values
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+    
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+    
+    /// Uniform in [0, n), n must not be 0
+    pub fn below(&mut self, n: u64) -> u64 {
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+    
+    /// Uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+/// Zipfian ranks over [0, items), rank 0 is the most popular.
+///
+/// This is the generator YCSB uses, from Gray et al., "Quickly Generating
+/// Billion-Record Synthetic Databases". Construction is O(items).
+pub struct Zipfian {
+    items: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    /// theta must be in (0, 1), YCSB uses 0.99
+    pub fn new(items: u64, theta: f64) -> Self {
+        let zetan: f64 = (1..=items)
+            .map(|i| 1.0 / (i as f64).powf(theta))
+            .sum();
+        let zeta2 = 1.0 + 0.5f64.powf(theta);
+        let eta = (1.0 - (2.0 / items as f64).powf(1.0 - theta))
+            / (1.0 - zeta2 / zetan);
+        Self {
+            items,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta,
+        }
+    }
+    
+    pub fn next(&self, rng: &mut Rng) -> u64 {
+        let u = rng.next_f64();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1;
+        }
+        let rank = self.items as f64
+            * (self.eta * u - self.eta + 1.0).powf(self.alpha);
+        (rank as u64).min(self.items - 1)
+    }
+}
+
+// FNV-1a of the rank, spreads the popular ranks over the key space
+fn scramble(rank: u64) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in rank.to_le_bytes().iter() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// Picks the index of the key each operation accesses. Shared by all client
+/// threads.
+pub struct KeyChooser {
+    distribution: Distribution,
+    // Keys loaded before the run
+    records: u64,
+    zipfian: Option<Zipfian>,
+    // Next index for Sequential
+    next: AtomicU64,
+}
+
+impl KeyChooser {
+    pub fn new(distribution: Distribution, records: u64, theta: f64) -> Self {
+        let zipfian = match distribution {
+            Distribution::Zipfian | Distribution::Latest => {
+                Some(Zipfian::new(records, theta))
+            }
+            _ => None,
+        };
+        Self {
+            distribution,
+            records,
+            zipfian,
+            next: AtomicU64::new(0),
+        }
+    }
+    
+    /// Index of the next key to access, out of the first inserted ones
+    pub fn next(&self, rng: &mut Rng, inserted: u64) -> u64 {
+        match self.distribution {
+            Distribution::Uniform => rng.below(inserted),
+            Distribution::Zipfian => {
+                let rank = self.zipfian.as_ref().unwrap().next(rng);
+                scramble(rank) % self.records
+            }
+            Distribution::Latest => {
+                let rank = self.zipfian.as_ref().unwrap().next(rng);
+                inserted - 1 - rank.min(inserted - 1)
+            }
+            Distribution::Sequential => {
+                self.next.fetch_add(1, Ordering::Relaxed) % inserted
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn mixes_add_up() {
+        let workloads = [
+            Workload::A, Workload::B, Workload::C,
+            Workload::D, Workload::E, Workload::F,
+        ];
+        for workload in &workloads {
+            let total: u64 = workload.mix().iter().map(|(_, p)| p).sum();
+            assert_eq!(total, 100, "{:?}", workload);
+        }
+    }
+    
+    #[test]
+    fn zipfian_favours_low_ranks() {
+        let zipfian = Zipfian::new(1000, 0.99);
+        let mut rng = Rng::new(1);
+        let mut counts = vec![0u64; 1000];
+        for _ in 0..100_000 {
+            counts[zipfian.next(&mut rng) as usize] += 1;
+        }
+        // Rank 0 gets about 1 / zetan of the draws, 13% for 1000 items
+        assert!(counts[0] > 10_000 && counts[0] < 16_000, "{}", counts[0]);
+        assert!(counts[0] > counts[1] && counts[1] > counts[10]);
+        assert!(counts[10] > counts[999]);
+    }
+    
+    #[test]
+    fn keys_sort_in_index_order() {
+        assert_eq!(key(42, 12), b"user00000042".to_vec());
+        assert!(key(9, 24) < key(10, 24));
+    }
+}
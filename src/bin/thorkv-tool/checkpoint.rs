@@ -0,0 +1,139 @@
+//! `thorkv-tool checkpoint`, reads checkpoint files written by
+//! CheckpointWriter.
+
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use thorkv::checkpoint::io::CheckpointReader;
+use thorkv::constants::CHECKPOINT_FILENAME;
+use thorkv::types::Error;
+
+use crate::format;
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["info", path] => info(Path::new(path)),
+        ["export", path] => export(Path::new(path), Format::JsonLines),
+        ["export", "--format", "jsonl", path] => {
+            export(Path::new(path), Format::JsonLines)
+        }
+        ["export", "--format", "csv", path] => {
+            export(Path::new(path), Format::Csv)
+        }
+        ["diff", a, b] => diff(Path::new(a), Path::new(b)),
+        _ => crate::usage(),
+    }
+}
+
+enum Format {
+    JsonLines,
+    Csv,
+}
+
+// Accept either a checkpoint file or the database directory holding it
+fn checkpoint_path(path: &Path) -> PathBuf {
+    if path.is_dir() {
+        path.join(CHECKPOINT_FILENAME)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+fn open_checkpoint(path: &Path) -> Result<CheckpointReader, Error> {
+    let path = checkpoint_path(path);
+    CheckpointReader::open(&path).map_err(|e| {
+        Error::new(format!("Cannot open {}: {}", path.display(), e))
+    })
+}
+
+fn info(path: &Path) -> Result<(), Error> {
+    let mut reader = open_checkpoint(path)?;
+    let mut entries: u64 = 0;
+    let mut key_bytes: u64 = 0;
+    let mut value_bytes: u64 = 0;
+    while let Some(record) = reader.read_ref()? {
+        entries += 1;
+        key_bytes += record.key.len() as u64;
+        value_bytes += record.value.len() as u64;
+    }
+    let path = checkpoint_path(path);
+    println!("{}: {} bytes", path.display(), path.metadata()?.len());
+    println!("format version: {:?}", reader.version());
+    println!("lsn: {}", reader.header().lsn);
+    println!("entries: {}", entries);
+    println!("key bytes: {}", key_bytes);
+    println!("value bytes: {}", value_bytes);
+    Ok(())
+}
+
+fn export(path: &Path, format: Format) -> Result<(), Error> {
+    let mut reader = open_checkpoint(path)?;
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    if let Format::Csv = format {
+        writeln!(out, "key,value,encoding")?;
+    }
+    while let Some(record) = reader.read_ref()? {
+        match format {
+            Format::JsonLines => writeln!(
+                out,
+                "{{{},{}}}",
+                format::json_member("key", record.key),
+                format::json_member("value", record.value)
+            )?,
+            Format::Csv => {
+                writeln!(out, "{}", format::csv_row(record.key, record.value))?
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Print the keys that were added, removed or changed going from a to b
+fn diff(a: &Path, b: &Path) -> Result<(), Error> {
+    let mut old = HashMap::new();
+    let mut reader = open_checkpoint(a)?;
+    while let Some((key, value)) = reader.read()? {
+        old.insert(key, value);
+    }
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let (mut added, mut changed) = (0, 0);
+    let mut reader = open_checkpoint(b)?;
+    while let Some(record) = reader.read_ref()? {
+        match old.remove(record.key) {
+            None => {
+                added += 1;
+                writeln!(out, "+ {}", format::display_key(record.key))?;
+            }
+            Some(value) if value != record.value => {
+                changed += 1;
+                writeln!(out, "~ {}", format::display_key(record.key))?;
+            }
+            Some(_) => {}
+        }
+    }
+    // Whatever is left was only in a
+    let mut removed: Vec<_> = old.into_keys().collect();
+    removed.sort();
+    for key in &removed {
+        writeln!(out, "- {}", format::display_key(key))?;
+    }
+    writeln!(
+        out,
+        "{} added, {} removed, {} changed",
+        added,
+        removed.len(),
+        changed
+    )?;
+    out.flush()?;
+
+    if added + changed + removed.len() > 0 {
+        return Err(Error::new("Checkpoints differ"));
+    }
+    Ok(())
+}
@@ -0,0 +1,154 @@
+//! Text encodings of keys and values for export and import.
+//!
+//! Keys and values are arbitrary bytes. They're written as text when they
+//! are valid UTF-8 and base64 encoded otherwise, and the output says which.
+
+use thorkv::types::Error;
+
+/// Quote s as a JSON string
+pub fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                res.push_str(&format!("\\u{:04x}", c as u32));
+            }
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// A JSON object member for data, `"name":"text"` if data is UTF-8 and
+/// `"name_base64":"..."` otherwise.
+pub fn json_member(name: &str, data: &[u8]) -> String {
+    match std::str::from_utf8(data) {
+        Ok(s) => format!("\"{}\":{}", name, json_string(s)),
+        Err(_) => format!("\"{}_base64\":\"{}\"", name, base64::encode(data)),
+    }
+}
+
+/// Quote a CSV field (RFC 4180) if it needs it
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// A `key,value,encoding` CSV row, both fields are base64 if either isn't
+/// UTF-8.
+pub fn csv_row(key: &[u8], value: &[u8]) -> String {
+    match (std::str::from_utf8(key), std::str::from_utf8(value)) {
+        (Ok(key), Ok(value)) => {
+            format!("{},{},utf8", csv_field(key), csv_field(value))
+        }
+        _ => {
+            let key = base64::encode(key);
+            format!("{},{},base64", key, base64::encode(value))
+        }
+    }
+}
+
+/// Short human-readable form of a key, for messages
+pub fn display_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(s) => format!("{:?}", s),
+        Err(_) => format!("base64:{}", base64::encode(key)),
+    }
+}
+
+/// Inverse of json_member, reads name or name_base64 from a JSON object
+pub fn parse_json_member(
+    object: &serde_json::Map<String, serde_json::Value>,
+    name: &str,
+) -> Result<Vec<u8>, Error> {
+    let base64_name = format!("{}_base64", name);
+    if let Some(value) = object.get(name) {
+        match value.as_str() {
+            Some(s) => Ok(s.as_bytes().to_vec()),
+            None => Err(Error::new(format!("\"{}\" is not a string", name))),
+        }
+    } else if let Some(value) = object.get(&base64_name) {
+        let s = value.as_str().ok_or_else(|| {
+            Error::new(format!("\"{}\" is not a string", base64_name))
+        })?;
+        base64::decode(s).map_err(|e| {
+            Error::new(format!("\"{}\": {}", base64_name, e))
+        })
+    } else {
+        Err(Error::new(format!("Missing \"{}\"", name)))
+    }
+}
+
+/// Parse a line written by export as JSON Lines
+pub fn parse_json_record(line: &str) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let value: serde_json::Value = serde_json::from_str(line)
+        .map_err(|e| Error::new(e.to_string()))?;
+    let object = value.as_object()
+        .ok_or_else(|| Error::new("Not a JSON object"))?;
+    let key = parse_json_member(object, "key")?;
+    let value = parse_json_member(object, "value")?;
+    Ok((key, value))
+}
+
+/// Inverse of csv_row. encoding is the row's encoding column, a missing
+/// column means utf8.
+pub fn parse_csv_fields(key: &str, value: &str, encoding: Option<&str>)
+    -> Result<(Vec<u8>, Vec<u8>), Error>
+{
+    match encoding.unwrap_or("utf8") {
+        "utf8" => Ok((key.as_bytes().to_vec(), value.as_bytes().to_vec())),
+        "base64" => {
+            let decode = |s: &str| {
+                base64::decode(s).map_err(|e| Error::new(e.to_string()))
+            };
+            Ok((decode(key)?, decode(value)?))
+        }
+        encoding => {
+            Err(Error::new(format!("Unknown encoding: {}", encoding)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_text_and_binary() {
+        assert_eq!(json_member("key", b"a\"b\n"), r#""key":"a\"b\n""#);
+        assert_eq!(json_member("key", &[0xff]), r#""key_base64":"/w==""#);
+        let row = csv_row(b"a,b", b"say \"hi\"");
+        assert_eq!(row, r#""a,b","say ""hi""",utf8"#);
+        assert_eq!(csv_row(b"a", &[0xff]), "YQ==,/w==,base64");
+    }
+
+    #[test]
+    fn parse_exported_records() {
+        let key = b"a\"b\n".to_vec();
+        let value = vec![0xff, 0];
+        let line = format!(
+            "{{{},{}}}",
+            json_member("key", &key),
+            json_member("value", &value)
+        );
+        let record = (key, value);
+        assert_eq!(parse_json_record(&line).unwrap(), record.clone());
+        assert!(parse_json_record(r#"{"value":"1"}"#).is_err());
+
+        let base64 = (base64::encode(&record.0), base64::encode(&record.1));
+        let parsed = parse_csv_fields(&base64.0, &base64.1, Some("base64"));
+        assert_eq!(parsed.unwrap(), record);
+        let parsed = parse_csv_fields("a", "1", None).unwrap();
+        assert_eq!(parsed, (b"a".to_vec(), b"1".to_vec()));
+    }
+}
@@ -0,0 +1,129 @@
+//! `thorkv-tool import`, bulk loads a file into a database with DB::ingest.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::iter;
+use std::path::Path;
+
+use thorkv::checkpoint::io::CheckpointReader;
+use thorkv::db::DB;
+use thorkv::types::Error;
+
+use crate::format;
+
+type Records = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>), Error>>>;
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (format, file, dir) = match args.as_slice() {
+        [file, dir] => (Format::from_extension(Path::new(file)), file, dir),
+        ["--format", format, file, dir] => match Format::from_name(format) {
+            Some(format) => (format, file, dir),
+            None => crate::usage(),
+        },
+        _ => crate::usage(),
+    };
+    import(format, Path::new(file), Path::new(dir))
+}
+
+enum Format {
+    JsonLines,
+    Csv,
+    Checkpoint,
+}
+
+impl Format {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "jsonl" => Some(Self::JsonLines),
+            "csv" => Some(Self::Csv),
+            "checkpoint" => Some(Self::Checkpoint),
+            _ => None,
+        }
+    }
+
+    // Anything that isn't .jsonl, .json or .csv is taken for a checkpoint
+    fn from_extension(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl") | Some("json") => Self::JsonLines,
+            Some("csv") => Self::Csv,
+            _ => Self::Checkpoint,
+        }
+    }
+}
+
+fn import(format: Format, file: &Path, dir: &Path) -> Result<(), Error> {
+    let records = match format {
+        Format::JsonLines => json_lines(open(file)?),
+        Format::Csv => csv(open(file)?)?,
+        Format::Checkpoint => {
+            let mut reader = CheckpointReader::open(file)?;
+            Box::new(iter::from_fn(move || reader.read().transpose()))
+        }
+    };
+    let db = DB::open(dir)?;
+    match db.ingest(records) {
+        Ok(count) => {
+            db.close()?;
+            println!("Imported {} records into {}", count, dir.display());
+            Ok(())
+        }
+        // The import stops at the first bad record, the ones before it stay
+        // loaded as DB::ingest documents. Checkpoint them so that a failed
+        // import always leaves them behind rather than only when a
+        // background checkpoint got to them first.
+        Err(err) => {
+            db.close_with_checkpoint()?;
+            Err(err)
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<File, Error> {
+    File::open(path).map_err(|e| {
+        Error::new(format!("Cannot open {}: {}", path.display(), e))
+    })
+}
+
+fn at_line(line: u64, err: Error) -> Error {
+    Error::new(format!("Line {}: {}", line, err))
+}
+
+fn json_lines(file: File) -> Records {
+    let lines = BufReader::new(file).lines().zip(1..);
+    Box::new(lines.filter_map(|(line, n)| match line {
+        Ok(line) if line.trim().is_empty() => None,
+        Ok(line) => {
+            Some(format::parse_json_record(&line).map_err(|e| at_line(n, e)))
+        }
+        Err(e) => Some(Err(e.into())),
+    }))
+}
+
+// The header names the key and value columns, plus an optional encoding
+// column as written by checkpoint export
+fn csv(file: File) -> Result<Records, Error> {
+    let mut reader = csv::Reader::from_reader(file);
+    let headers = reader.headers().map_err(|e| Error::new(e.to_string()))?;
+    let column = |name| headers.iter().position(|header| header == name);
+    let key = column("key")
+        .ok_or_else(|| Error::new("CSV header has no key column"))?;
+    let value = column("value")
+        .ok_or_else(|| Error::new("CSV header has no value column"))?;
+    let encoding = column("encoding");
+    Ok(Box::new(reader.into_records().map(move |record| {
+        let record = record.map_err(|e| Error::new(e.to_string()))?;
+        let line = record.position().map_or(0, |pos| pos.line());
+        let field = |i| {
+            record.get(i).ok_or_else(|| {
+                at_line(line, Error::new("Missing column"))
+            })
+        };
+        let encoding = match encoding {
+            Some(i) => Some(field(i)?),
+            None => None,
+        };
+        format::parse_csv_fields(field(key)?, field(value)?, encoding)
+            .map_err(|e| at_line(line, e))
+    })))
+}
@@ -0,0 +1,61 @@
+//! Offline tools for inspecting and repairing a ThorKV database directory.
+//!
+//! The database should not be open while a command that modifies it runs,
+//! those commands take the directory lock and fail if it's held.
+
+use std::env;
+use std::process;
+
+mod checkpoint;
+mod format;
+mod import;
+mod wal;
+
+const USAGE: &str = "\
+Usage: thorkv-tool <command> [options] <path>...
+
+WAL commands, <path> is the database directory:
+    wal dump [--json]   Print every entry in the WAL, one per line
+    wal verify          Check the WAL and report the first bad entry
+    wal truncate        Cut the WAL after the last complete transaction,
+                        dropping a torn or corrupt tail
+
+Checkpoint commands, <path> is a checkpoint file or a database directory:
+    checkpoint info     Print the header and the number of entries
+    checkpoint export [--format jsonl|csv]
+                        Print every key-value pair as JSON Lines (default)
+                        or CSV. Keys and values that aren't UTF-8 are base64
+                        encoded
+    checkpoint diff <path-a> <path-b>
+                        List the keys added (+), removed (-) and changed (~)
+                        going from a to b
+
+Import:
+    import [--format jsonl|csv|checkpoint] <file> <db-dir>
+                        Bulk load key-value pairs into the database, which
+                        must not be open. The format defaults to the file
+                        extension (.jsonl, .json, .csv), anything else is
+                        read as a checkpoint. JSON Lines and CSV are read as
+                        written by checkpoint export. On a bad record the
+                        ones before it stay imported
+";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let res = match args.first().map(String::as_str) {
+        Some("wal") => wal::run(&args[1..]),
+        Some("checkpoint") => checkpoint::run(&args[1..]),
+        Some("import") => import::run(&args[1..]),
+        _ => usage(),
+    };
+    if let Err(err) = res {
+        eprintln!("thorkv-tool: {}", err);
+        process::exit(1);
+    }
+}
+
+/// Print usage and exit, for invalid arguments
+pub fn usage() -> ! {
+    eprint!("{}", USAGE);
+    process::exit(2);
+}
@@ -0,0 +1,260 @@
+//! `thorkv-tool wal`, looks inside wal.log.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use thorkv::constants::LOG_FILENAME;
+use thorkv::db::lock::DirLock;
+use thorkv::log::io::LogReader;
+use thorkv::log::logentry::LogEntryRef;
+use thorkv::types::{Error, Lsn, Xid};
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["dump", dir] => dump(Path::new(dir), false),
+        ["dump", "--json", dir] => dump(Path::new(dir), true),
+        ["verify", dir] => verify(Path::new(dir)),
+        ["truncate", dir] => truncate(Path::new(dir)),
+        _ => crate::usage(),
+    }
+}
+
+fn log_path(dir: &Path) -> PathBuf {
+    dir.join(LOG_FILENAME)
+}
+
+fn open_log(dir: &Path) -> Result<LogReader, Error> {
+    let path = log_path(dir);
+    LogReader::open(&path).map_err(|e| {
+        Error::new(format!("Cannot open {}: {}", path.display(), e))
+    })
+}
+
+fn dump(dir: &Path, json: bool) -> Result<(), Error> {
+    let mut reader = open_log(dir)?;
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    loop {
+        let lsn = reader.offset();
+        let log = match reader.try_read_ref()? {
+            Some(log) => log,
+            None => break,
+        };
+        if json {
+            writeln!(out, "{}", format_json(lsn, &log))?;
+        } else {
+            writeln!(out, "{}", format_text(lsn, &log))?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+enum Field {
+    Int(u64),
+    Str(String),
+    Null,
+}
+
+// Name and fields of an entry, keys and values are shown by size only
+fn describe(log: &LogEntryRef) -> (&'static str, Vec<(&'static str, Field)>) {
+    let len = |data: Option<&[u8]>| match data {
+        Some(data) => Field::Int(data.len() as u64),
+        None => Field::Null,
+    };
+    let xid_only = |xid| vec![("xid", Field::Int(xid))];
+    match *log {
+        LogEntryRef::XBegin { xid } => ("XBegin", xid_only(xid)),
+        LogEntryRef::XCommit { xid, timestamp } => {
+            ("XCommit", vec![
+                ("xid", Field::Int(xid)),
+                ("timestamp", Field::Int(timestamp)),
+            ])
+        }
+        LogEntryRef::XAbort { xid } => ("XAbort", xid_only(xid)),
+        LogEntryRef::Update { xid, key, value, previous_value } => {
+            ("Update", vec![
+                ("xid", Field::Int(xid)),
+                ("key_len", len(Some(key))),
+                ("value_len", len(value)),
+                ("previous_value_len", len(previous_value)),
+            ])
+        }
+        LogEntryRef::CPhase(phase) => {
+            ("CPhase", vec![("phase", Field::Str(format!("{:?}", phase)))])
+        }
+        LogEntryRef::Operation { xid, key, value } => {
+            ("Operation", vec![
+                ("xid", Field::Int(xid)),
+                ("key_len", len(Some(key))),
+                ("value_len", len(value)),
+            ])
+        }
+    }
+}
+
+fn format_text(lsn: Lsn, log: &LogEntryRef) -> String {
+    let (name, fields) = describe(log);
+    let mut res = format!("{:>12} {}", lsn, name);
+    for (field, value) in fields {
+        match value {
+            Field::Int(v) => res += &format!(" {}={}", field, v),
+            Field::Str(v) => res += &format!(" {}={}", field, v),
+            Field::Null => res += &format!(" {}=-", field),
+        }
+    }
+    res
+}
+
+// Field names and strings are plain identifiers, nothing needs escaping
+fn format_json(lsn: Lsn, log: &LogEntryRef) -> String {
+    let (name, fields) = describe(log);
+    let mut res = format!("{{\"lsn\":{},\"type\":\"{}\"", lsn, name);
+    for (field, value) in fields {
+        match value {
+            Field::Int(v) => res += &format!(",\"{}\":{}", field, v),
+            Field::Str(v) => res += &format!(",\"{}\":\"{}\"", field, v),
+            Field::Null => res += &format!(",\"{}\":null", field),
+        }
+    }
+    res.push('}');
+    res
+}
+
+/// Result of reading a log from start to end
+#[derive(Default)]
+struct Scan {
+    entries: u64,
+    committed: u64,
+    aborted: u64,
+    // Transactions without a commit or abort, with the offset of their
+    // XBegin
+    pending: HashMap<Xid, Lsn>,
+    // Offset just past the last entry after which no transaction was
+    // pending, which is where recovery stops replaying
+    valid_end: Lsn,
+    // The first entry that couldn't be read
+    error: Option<Error>,
+}
+
+fn scan(reader: &mut LogReader) -> Scan {
+    let mut scan = Scan::default();
+    loop {
+        let lsn = reader.offset();
+        let log = match reader.try_read_ref() {
+            Ok(Some(log)) => log,
+            Ok(None) => break,
+            Err(err) => {
+                scan.error = Some(err);
+                break;
+            }
+        };
+        scan.entries += 1;
+        match log {
+            LogEntryRef::XBegin { xid } => {
+                scan.pending.insert(xid, lsn);
+            }
+            LogEntryRef::XCommit { xid, .. } => {
+                scan.pending.remove(&xid);
+                scan.committed += 1;
+            }
+            LogEntryRef::XAbort { xid } => {
+                scan.pending.remove(&xid);
+                scan.aborted += 1;
+            }
+            _ => {}
+        }
+        if scan.pending.is_empty() {
+            scan.valid_end = reader.offset();
+        }
+    }
+    scan
+}
+
+fn verify(dir: &Path) -> Result<(), Error> {
+    let mut reader = open_log(dir)?;
+    let res = scan(&mut reader);
+    println!("{}: {} bytes", log_path(dir).display(), reader.len());
+    println!("entries: {}", res.entries);
+    println!("committed transactions: {}", res.committed);
+    println!("aborted transactions: {}", res.aborted);
+    if !res.pending.is_empty() {
+        let mut pending: Vec<_> = res.pending.iter().collect();
+        pending.sort_by_key(|(_, lsn)| **lsn);
+        for (xid, lsn) in pending {
+            println!("incomplete transaction: xid {} at offset {}", xid, lsn);
+        }
+    }
+    println!("valid up to offset: {}", res.valid_end);
+    match res.error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+fn truncate(dir: &Path) -> Result<(), Error> {
+    // Fails if the database is open, it would keep appending after the cut
+    let _lock = DirLock::exclusive(dir)?;
+    let mut reader = open_log(dir)?;
+    let res = scan(&mut reader);
+    let len = reader.len();
+    let path = log_path(dir);
+    if res.valid_end == len {
+        println!("{} has no tail to truncate", path.display());
+        return Ok(());
+    }
+    if let Some(err) = &res.error {
+        println!("{}", err);
+    }
+    let file = OpenOptions::new().write(true).open(&path)?;
+    file.set_len(res.valid_end)?;
+    file.sync_all()?;
+    println!(
+        "Truncated {} from {} to {} bytes",
+        path.display(),
+        len,
+        res.valid_end
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use thorkv::log::io::{encode, LogWriter};
+    use thorkv::log::logentry::LogEntry;
+
+    #[test]
+    fn scan_stops_at_torn_tail() {
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-tool-wal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = log_path(&dir);
+        let _ = std::fs::remove_file(&path);
+
+        let mut writer = LogWriter::open(&path).unwrap();
+        writer.write(&LogEntry::XBegin { xid: 1 }).unwrap();
+        writer.write(&LogEntry::XCommit { xid: 1, timestamp: 1 }).unwrap();
+        let committed_end = std::fs::metadata(&path).unwrap().len();
+        writer.write(&LogEntry::XBegin { xid: 2 }).unwrap();
+        let commit = encode(&LogEntry::XCommit { xid: 2, timestamp: 2 });
+        writer.write_bytes(&commit[..commit.len() - 1]).unwrap();
+
+        let res = scan(&mut open_log(&dir).unwrap());
+        assert_eq!(res.entries, 3);
+        assert_eq!(res.committed, 1);
+        assert_eq!(res.pending.len(), 1);
+        assert_eq!(res.valid_end, committed_end);
+        assert!(res.error.is_some());
+
+        truncate(&dir).unwrap();
+        let res = scan(&mut open_log(&dir).unwrap());
+        assert_eq!(res.entries, 2);
+        assert!(res.pending.is_empty() && res.error.is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,45 +1,400 @@
-use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom};
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use byteorder::{BigEndian, ReadBytesExt};
+
+use crate::env::{Env, ReadableFile, RealEnv, WritableFile};
+use crate::types::{Error, Lsn};
 use crate::util::serde;
+use crate::util::serde::{Deserialize, FormatVersion, Serialize};
+use crate::util::serde::{CURRENT_VERSION, LEGACY_USIZE_LEN};
 
 const BATCH_SIZE: u64 = 512;
 
+const MAGIC: &[u8; 4] = b"TKVC";
+
+/// Metadata stored at the start of a checkpoint file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CheckpointHeader {
+    /// Position of the checkpoint's point of consistency in the WAL. Every
+    /// transaction logged before it is contained in the checkpoint, the ones
+    /// after it have to be replayed from the log.
+    pub lsn: Lsn,
+}
+
+// Size of a serialized CheckpointHeader: magic, version and lsn
+const HEADER_LEN: usize = 4 + 1 + 8;
+
+impl Serialize for CheckpointHeader {
+    fn serialize_into(&self, res: &mut Vec<u8>) {
+        res.extend_from_slice(MAGIC);
+        res.push(CURRENT_VERSION as u8);
+        serde::serialize_u64(res, self.lsn);
+    }
+}
+
+/// The header carries its own version, the version argument is ignored.
+impl<'a> Deserialize<'a> for CheckpointHeader {
+    fn deserialize(rdr: &mut Cursor<&'a [u8]>, _version: FormatVersion)
+        -> Result<Self, Error>
+    {
+        let mut magic = [0u8; 4];
+        rdr.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new("Not a checkpoint file"));
+        }
+        read_version(rdr)?;
+        let lsn = rdr.read_u64::<BigEndian>()?;
+        Ok(Self { lsn })
+    }
+}
+
+fn read_version(rdr: &mut Cursor<&[u8]>) -> Result<FormatVersion, Error> {
+    let version = rdr.read_u8()?;
+    FormatVersion::from_u8(version).ok_or_else(|| {
+        Error::new(format!("Unsupported checkpoint version: {}", version))
+    })
+}
+
+/// A key-value pair stored in a checkpoint
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CheckpointRecord<'a> {
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+}
+
+impl<'a> Serialize for CheckpointRecord<'a> {
+    fn serialize_into(&self, res: &mut Vec<u8>) {
+        serde::serialize_u8_vec(res, self.key);
+        serde::serialize_u8_vec(res, self.value);
+    }
+}
+
+impl<'a> Deserialize<'a> for CheckpointRecord<'a> {
+    fn deserialize(rdr: &mut Cursor<&'a [u8]>, version: FormatVersion)
+        -> Result<Self, Error>
+    {
+        let key = serde::deserialize_u8_slice(rdr, version)?;
+        let value = serde::deserialize_u8_slice(rdr, version)?;
+        Ok(Self { key, value })
+    }
+}
+
+/// Writes a checkpoint to disk
+///
+/// Checkpoint format
+///
+///  -----------------------------------------------------------------
+/// | magic | version | lsn | key_1 | value_1 | ... | key_N | value_N |
+///  -----------------------------------------------------------------
+///
+/// version is the serde::FormatVersion of the key and value lengths, V1
+/// checkpoints are still read but new ones are always written in the
+/// current version. V1 is the oldest checkpoint format there is: checkpoints
+/// have had this header since the first release that could write them.
+///
+/// The checkpoint is written to a temporary file that is renamed over the
+/// previous checkpoint by `commit`, so a crash in the middle of taking a
+/// checkpoint never destroys the last complete one.
 pub struct CheckpointWriter {
-    file: File,
+    env: Arc<dyn Env>,
+    filepath: PathBuf,
+    tmp_filepath: PathBuf,
+    file: BufWriter<Box<dyn WritableFile>>,
     written: u64,
+    // Reused to serialize each record
+    buf: Vec<u8>,
 }
 
 impl CheckpointWriter {
-    pub fn new() -> Self {
-        Self::with_path("checkpoint.bin")
+    pub fn create(filepath: &Path, header: CheckpointHeader)
+        -> Result<Self, Error>
+    {
+        Self::create_with_env(Arc::new(RealEnv), filepath, header)
     }
     
-    pub fn with_path(filepath: &str) -> Self {
-        let file = OpenOptions::new()
-            .create(true)
-            .open(filepath)
-            .unwrap();
-        Self {
+    pub fn create_with_env(
+        env: Arc<dyn Env>,
+        filepath: &Path,
+        header: CheckpointHeader,
+    ) -> Result<Self, Error> {
+        let mut tmp_filepath = filepath.as_os_str().to_owned();
+        tmp_filepath.push(".tmp");
+        let tmp_filepath = PathBuf::from(tmp_filepath);
+        let mut file = BufWriter::new(env.create(&tmp_filepath)?);
+        file.write_all(&header.serialize())?;
+        Ok(Self {
+            env,
+            filepath: filepath.to_path_buf(),
+            tmp_filepath,
             file,
             written: 0,
-        }
+            buf: Vec::new(),
+        })
     }
     
-    pub fn append(&mut self, key: &[u8], value: &[u8]) {
-        let mut res = vec![];
-        serde::serialize_u8_vec(&mut res, key);
-        serde::serialize_u8_vec(&mut res, value);
-        self.file.write_all(&res).unwrap();
+    pub fn append(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.buf.clear();
+        CheckpointRecord { key, value }.serialize_into(&mut self.buf);
+        self.file.write_all(&self.buf)?;
         
         self.written += 1;
         if self.written >= BATCH_SIZE {
-            self.file.sync_data().unwrap();
+            self.flush()?;
             self.written = 0;
         }
+        Ok(())
+    }
+    
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.file.flush()?;
+        self.file.get_mut().sync()?;
+        Ok(())
+    }
+    
+    /// Make the checkpoint durable and replace the previous one with it
+    pub fn commit(mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.env.rename(&self.tmp_filepath, &self.filepath)?;
+        if let Some(dir) = self.filepath.parent() {
+            self.env.sync_dir(dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// A key-value pair read from a checkpoint
+pub type KeyValue = (Vec<u8>, Vec<u8>);
+
+/// Reads back a checkpoint written by CheckpointWriter
+pub struct CheckpointReader {
+    file: BufReader<Box<dyn ReadableFile>>,
+    version: FormatVersion,
+    header: CheckpointHeader,
+    // Holds the last record read, read_ref borrows from it
+    buf: Vec<u8>,
+    file_len: u64,
+    // Offset just past the last byte read
+    offset: u64,
+}
+
+impl CheckpointReader {
+    pub fn open(filepath: &Path) -> Result<Self, Error> {
+        Self::open_with_env(&RealEnv, filepath)
     }
     
-    pub fn flush(&self) {
-        self.file.sync_data().unwrap();
+    pub fn open_with_env(env: &dyn Env, filepath: &Path)
+        -> Result<Self, Error>
+    {
+        let mut file = env.open_read(filepath)?;
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut file = BufReader::new(file);
+        let mut header_buf = [0u8; HEADER_LEN];
+        file.read_exact(&mut header_buf).map_err(|_| {
+            Error::new(format!(
+                "{} is not a checkpoint file", filepath.display()
+            ))
+        })?;
+        let header = serde::from_bytes(&header_buf, CURRENT_VERSION)
+            .map_err(|err| {
+                Error::new(format!("{}: {}", filepath.display(), err))
+            })?;
+        let version = &header_buf[MAGIC.len()..];
+        let version = read_version(&mut Cursor::new(version))?;
+        Ok(Self {
+            file,
+            version,
+            header,
+            buf: Vec::new(),
+            file_len,
+            offset: HEADER_LEN as u64,
+        })
+    }
+    
+    pub fn header(&self) -> &CheckpointHeader {
+        &self.header
+    }
+    
+    /// Format version the records are encoded with
+    pub fn version(&self) -> FormatVersion {
+        self.version
+    }
+    
+    /// Read the next key-value pair, returning None at the end of the
+    /// checkpoint.
+    pub fn read(&mut self) -> Result<Option<KeyValue>, Error> {
+        let record = self.read_ref()?;
+        Ok(record.map(|record| (record.key.to_vec(), record.value.to_vec())))
+    }
+    
+    /// Like read, but the key and value borrow from the reader's buffer,
+    /// which is reused by the next call.
+    pub fn read_ref(&mut self)
+        -> Result<Option<CheckpointRecord<'_>>, Error>
+    {
+        if self.file.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        self.buf.clear();
+        self.read_bytes()?;
+        self.read_bytes()?;
+        let record = serde::from_bytes(&self.buf, self.version)?;
+        Ok(Some(record))
+    }
+    
+    // Append the next length-prefixed byte string, prefix included, to buf
+    fn read_bytes(&mut self) -> Result<(), Error> {
+        let offset = self.offset;
+        let prefix_start = self.buf.len();
+        let size = match self.version {
+            FormatVersion::V1 => {
+                let mut size_buf = [0u8; LEGACY_USIZE_LEN];
+                self.file.read_exact(&mut size_buf)?;
+                self.buf.extend_from_slice(&size_buf);
+                serde::deserialize_legacy_usize(&mut Cursor::new(&size_buf))?
+                    as u64
+            }
+            FormatVersion::V2 | FormatVersion::V3 => {
+                let size = serde::read_varint(&mut self.file)?;
+                serde::serialize_varint(&mut self.buf, size);
+                size
+            }
+        };
+        let start = self.buf.len();
+        // Don't trust the size before allocating for it
+        let end = offset
+            .saturating_add((start - prefix_start) as u64)
+            .saturating_add(size);
+        if end > self.file_len {
+            return Err(Error::new(format!(
+                "Incomplete checkpoint record at offset {}", offset
+            )));
+        }
+        self.buf.resize(start + size as usize, 0);
+        self.file.read_exact(&mut self.buf[start..])?;
+        self.offset = end;
+        Ok(())
+    }
+}
+
+/// Fsync a directory so that renames and newly created files inside it are
+/// durable.
+pub fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    RealEnv.sync_dir(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    
+    #[test]
+    fn write_and_read_checkpoint() {
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-checkpoint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let filepath = dir.join("checkpoint.bin");
+        
+        let header = CheckpointHeader { lsn: 42 };
+        let mut writer = CheckpointWriter::create(&filepath, header).unwrap();
+        writer.append(b"foo", b"bar").unwrap();
+        writer.append(b"baz", b"").unwrap();
+        // Nothing is visible until the checkpoint is committed
+        assert!(!filepath.exists());
+        writer.commit().unwrap();
+        
+        let mut reader = CheckpointReader::open(&filepath).unwrap();
+        assert_eq!(*reader.header(), header);
+        let foo = (b"foo".to_vec(), b"bar".to_vec());
+        assert_eq!(reader.read().unwrap(), Some(foo));
+        assert_eq!(reader.read().unwrap(), Some((b"baz".to_vec(), vec![])));
+        assert_eq!(reader.read().unwrap(), None);
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn read_checkpoint_with_native_lengths() {
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-checkpoint-v1-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let filepath = dir.join("checkpoint.bin");
+        
+        let mut v1 = MAGIC.to_vec();
+        v1.push(FormatVersion::V1 as u8);
+        v1.extend_from_slice(&42u64.to_be_bytes());
+        v1.extend_from_slice(&3usize.to_be_bytes());
+        v1.extend_from_slice(b"foo");
+        v1.extend_from_slice(&3usize.to_be_bytes());
+        v1.extend_from_slice(b"bar");
+        fs::write(&filepath, &v1).unwrap();
+        
+        let mut reader = CheckpointReader::open(&filepath).unwrap();
+        assert_eq!(reader.header().lsn, 42);
+        let foo = (b"foo".to_vec(), b"bar".to_vec());
+        assert_eq!(reader.read().unwrap(), Some(foo));
+        assert_eq!(reader.read().unwrap(), None);
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn read_truncated_checkpoint() {
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-checkpoint-torn-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let filepath = dir.join("checkpoint.bin");
+        
+        let header = CheckpointHeader { lsn: 42 };
+        let mut writer = CheckpointWriter::create(&filepath, header).unwrap();
+        writer.append(b"foo", b"bar").unwrap();
+        writer.commit().unwrap();
+        // A key that claims to be far longer than what's left of the file
+        let mut torn = fs::read(&filepath).unwrap();
+        serde::serialize_varint(&mut torn, 1 << 40);
+        torn.extend_from_slice(b"baz");
+        fs::write(&filepath, &torn).unwrap();
+        
+        let mut reader = CheckpointReader::open(&filepath).unwrap();
+        let foo = (b"foo".to_vec(), b"bar".to_vec());
+        assert_eq!(reader.read().unwrap(), Some(foo));
+        let err = reader.read().unwrap_err();
+        assert!(err.to_string().contains("Incomplete checkpoint record"));
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    proptest! {
+        #[test]
+        fn header_round_trip(lsn in any::<Lsn>()) {
+            let header = CheckpointHeader { lsn };
+            let bytes = header.serialize();
+            prop_assert_eq!(bytes.len(), HEADER_LEN);
+            let header_out: CheckpointHeader =
+                serde::from_bytes(&bytes, CURRENT_VERSION).unwrap();
+            prop_assert_eq!(header_out, header);
+        }
+        
+        #[test]
+        fn record_round_trip(
+            key in vec(any::<u8>(), 0..64),
+            value in vec(any::<u8>(), 0..1024),
+        ) {
+            let record = CheckpointRecord { key: &key, value: &value };
+            let bytes = record.serialize();
+            let record_out: CheckpointRecord =
+                serde::from_bytes(&bytes, CURRENT_VERSION).unwrap();
+            prop_assert_eq!(record_out, record);
+            for len in 0..bytes.len() {
+                let res: Result<CheckpointRecord, _> =
+                    serde::from_bytes(&bytes[..len], CURRENT_VERSION);
+                prop_assert!(res.is_err());
+            }
+        }
     }
 }
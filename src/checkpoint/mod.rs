@@ -1,58 +1,133 @@
-use std::sync::Arc;
-use std::time::Duration;
-use std::thread;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant};
+use std::thread::{self, JoinHandle};
 
-use crate::constants::CHECKPOINT_INTERVAL_SECS;
-use crate::db::DBRef;
+use tracing::{info, info_span, warn};
+
+use crate::constants::{CHECKPOINT_INTERVAL_SECS, REAPER_INTERVAL_MILLIS};
+use crate::db::DB;
 use crate::transaction::table::TransactionTableRef;
-use crate::types::{CheckpointPhase, Xid};
+use crate::types::{CheckpointPhase, Error, Xid};
 
 pub mod io;
 
 const BUSY_WAIT_INTERVAL_MILLIS: u64 = 1;
 
-pub struct Checkpointer {
-    db: DBRef,
-    xtable: TransactionTableRef,
+pub(crate) struct Checkpointer {
+    // Weak so that the checkpointer doesn't keep the DB alive, dropping the
+    // last DBRef closes the DB which stops the checkpointer.
+    db: Weak<DB>,
+    stopped: Mutex<bool>,
+    wakeup: Condvar,
 }
 
 impl Checkpointer {
-    pub fn new(db: DBRef, xtable: TransactionTableRef) -> Self {
-        Self { db, xtable }
+    pub fn new(db: Weak<DB>) -> Self {
+        Self {
+            db,
+            stopped: Mutex::new(false),
+            wakeup: Condvar::new(),
+        }
+    }
+    
+    /// Ask the checkpointer thread to exit. A checkpoint that is already
+    /// running is finished first.
+    pub fn stop(&self) {
+        let mut stopped = self.stopped.lock().unwrap();
+        *stopped = true;
+        self.wakeup.notify_all();
+    }
+    
+    // Sleep for interval, returns false if stopped meanwhile
+    fn sleep(&self, interval: Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap();
+        let (stopped, _) = self.wakeup
+            .wait_timeout_while(stopped, interval, |stopped| !*stopped)
+            .unwrap();
+        !*stopped
     }
 }
 
-/// Start periodic checkpointer running on background thread
-pub fn start_checkpointer(checkpointer: Arc<Checkpointer>) {
-    tokio::spawn(async move {
-        thread::sleep(Duration::from_secs(CHECKPOINT_INTERVAL_SECS));
-        run_checkpointer(checkpointer);        
-    });
+/// Start periodic checkpointer running on background thread. It also
+/// aborts the transactions that ran past their timeout.
+pub(crate) fn start_checkpointer(checkpointer: Arc<Checkpointer>)
+    -> std::io::Result<JoinHandle<()>>
+{
+    thread::Builder::new()
+        .name(String::from("thorkv-checkpointer"))
+        .spawn(move || {
+            let interval = Duration::from_secs(CHECKPOINT_INTERVAL_SECS);
+            let reaper_interval =
+                Duration::from_millis(REAPER_INTERVAL_MILLIS);
+            let mut last_checkpoint = Instant::now();
+            while checkpointer.sleep(reaper_interval) {
+                let db = match checkpointer.db.upgrade() {
+                    Some(db) => db,
+                    None => break,
+                };
+                db.abort_expired();
+                if last_checkpoint.elapsed() < interval {
+                    continue;
+                }
+                last_checkpoint = Instant::now();
+                // TODO: Retry
+                if let Err(err) = db.checkpoint() {
+                    warn!(%err, "Background checkpoint failed");
+                }
+            }
+        })
 }
 
-fn run_checkpointer(checkpointer: Arc<Checkpointer>) {
-    let db = checkpointer.db.clone();
-    let xtable = checkpointer.xtable.clone();
-    
-    let prepare_xid = db.set_phase(CheckpointPhase::PREPARE);
-    wait_oldest_xid_gte(xtable.clone(), prepare_xid);
-    let resolve_xid = db.set_phase(CheckpointPhase::RESOLVE);
-    wait_oldest_xid_gte(xtable.clone(), resolve_xid);
-    db.set_phase(CheckpointPhase::CAPTURE);
-    db.save_checkpoint();
-    let complete_xid = db.set_phase(CheckpointPhase::COMPLETE);
-    wait_oldest_xid_gte(xtable.clone(), complete_xid);
-    db.post_checkpoint();
-    db.set_phase(CheckpointPhase::REST);
+pub(crate) fn run_checkpointer(db: &DB, xtable: &TransactionTableRef)
+    -> Result<(), Error>
+{
+    let span = info_span!("checkpoint");
+    let _guard = span.enter();
+    let start = Instant::now();
+    in_phase(db, CheckpointPhase::PREPARE, |xid| {
+        wait_oldest_xid_gte(db, xtable, xid)
+    })?;
+    in_phase(db, CheckpointPhase::RESOLVE, |xid| {
+        wait_oldest_xid_gte(db, xtable, xid)
+    })?;
+    let res = in_phase(db, CheckpointPhase::CAPTURE, |_| {
+        db.save_checkpoint()
+    })?;
+    in_phase(db, CheckpointPhase::COMPLETE, |xid| {
+        wait_oldest_xid_gte(db, xtable, xid);
+        db.post_checkpoint();
+    })?;
+    db.set_phase(CheckpointPhase::REST)?;
+    if res.is_ok() {
+        db.metrics().checkpoints.inc();
+        let elapsed_ms = start.elapsed().as_millis() as u64;
+        info!(elapsed_ms, "Checkpoint completed");
+    }
+    res
+}
+
+// Switch to phase and run f with the xid set_phase returns, inside a span
+// covering the time spent in that phase
+fn in_phase<T>(
+    db: &DB,
+    phase: CheckpointPhase,
+    f: impl FnOnce(Xid) -> T,
+) -> Result<T, Error> {
+    let xid = db.set_phase(phase)?;
+    let span = info_span!("checkpoint_phase", ?phase, xid);
+    Ok(span.in_scope(|| f(xid)))
 }
 
-// Busy wait until oldest xid >= xid
-fn wait_oldest_xid_gte(xtable: TransactionTableRef, xid: Xid) {
+// Busy wait until the oldest committing xid >= xid, aborting transactions
+// that run past their timeout meanwhile. Transactions that are open but not
+// committing aren't waited for.
+fn wait_oldest_xid_gte(db: &DB, xtable: &TransactionTableRef, xid: Xid) {
     loop {
-        let oldest_xid = xtable.oldest_xid();
-        if oldest_xid.is_some() && oldest_xid.unwrap() >= xid {
+        let oldest_xid = xtable.oldest_committing_xid();
+        if oldest_xid.is_none() || oldest_xid.unwrap() >= xid {
             break;
         }
+        db.abort_expired();
         thread::sleep(Duration::from_millis(BUSY_WAIT_INTERVAL_MILLIS));
     }
 }
@@ -1,2 +1,10 @@
 // Checkpoint
 pub const CHECKPOINT_INTERVAL_SECS: u64 = 30;
+
+// How often transactions past their timeout are looked for
+pub const REAPER_INTERVAL_MILLIS: u64 = 1000;
+
+// Files inside the database directory
+pub const LOCK_FILENAME: &str = "LOCK";
+pub const LOG_FILENAME: &str = "wal.log";
+pub const CHECKPOINT_FILENAME: &str = "checkpoint.bin";
@@ -0,0 +1,278 @@
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::checkpoint::run_checkpointer;
+use crate::constants::{CHECKPOINT_FILENAME, LOG_FILENAME};
+use crate::db::{DBOptions, DBRef, RecoveryTarget, DB};
+use crate::env::Env;
+use crate::types::Error;
+
+impl DB {
+    /// Write a consistent copy of the database to a new directory while it
+    /// keeps serving reads and writes.
+    ///
+    /// A checkpoint is taken and copied along with the log up to its durable
+    /// end, so the backup holds every transaction that was durable when the
+    /// log was copied. The backup directory is a database directory itself,
+    /// it can be opened directly or restored with `restore_from`.
+    pub fn backup_to<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        self.check_writable()?;
+        let path = path.as_ref();
+        let env = &*self.options.env;
+        if env.exists(path) && !env.list(path)?.is_empty() {
+            return Err(Error::new(format!(
+                "Backup directory {} is not empty",
+                path.display()
+            )));
+        }
+        env.create_dir_all(path)?;
+        
+        {
+            // Keep the checkpoint from being replaced while it's copied
+            let _checkpoint_guard = self.checkpoint_lock.lock().unwrap();
+            run_checkpointer(self, &self.xtable)?;
+            let checkpoint = self.path.join(CHECKPOINT_FILENAME);
+            let dst = path.join(CHECKPOINT_FILENAME);
+            copy_file(env, &checkpoint, &dst, None)?;
+        }
+        // The log is only appended to while the DB is open. Its durable end
+        // is past the checkpoint's position, save_checkpoint waits for that.
+        let log_end = self.log.as_ref().unwrap().durable_lsn();
+        let log = self.path.join(LOG_FILENAME);
+        copy_file(env, &log, &path.join(LOG_FILENAME), Some(log_end))?;
+        env.sync_dir(path)?;
+        Ok(())
+    }
+    
+    /// Create a database in path from a backup written by `backup_to` and
+    /// open it.
+    ///
+    /// Fails if path already holds a database.
+    pub fn restore_from<P, Q>(backup: P, path: Q) -> Result<DBRef, Error>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>
+    {
+        let options = DBOptions::default();
+        Self::restore(backup.as_ref(), path.as_ref(), options, None)
+    }
+    
+    /// Like `restore_from`, but only replay the backed up log up to target.
+    ///
+    /// This is point-in-time recovery, e.g. restoring the state right before
+    /// a bad transaction. The backup's checkpoint has to be older than the
+    /// target. The WAL is only ever appended to, so to reach past the time
+    /// of the backup its wal.log can be replaced with a newer copy of the
+    /// database's. The log after the target is dropped from the restored
+    /// database, the backup itself is left untouched.
+    pub fn restore_to<P, Q>(backup: P, path: Q, target: RecoveryTarget)
+        -> Result<DBRef, Error>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>
+    {
+        let options = DBOptions::default();
+        Self::restore(backup.as_ref(), path.as_ref(), options, Some(target))
+    }
+    
+    /// Like `restore_from`, but the backup is read and the database created
+    /// through the env in options.
+    pub fn restore_with_options<P, Q>(
+        backup: P,
+        path: Q,
+        options: DBOptions,
+    ) -> Result<DBRef, Error>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>
+    {
+        Self::restore(backup.as_ref(), path.as_ref(), options, None)
+    }
+    
+    fn restore(
+        backup: &Path,
+        path: &Path,
+        options: DBOptions,
+        target: Option<RecoveryTarget>,
+    ) -> Result<DBRef, Error> {
+        let env = options.env.clone();
+        let checkpoint = backup.join(CHECKPOINT_FILENAME);
+        if !env.exists(&checkpoint) {
+            return Err(
+                Error::new(format!("No backup at {}", backup.display()))
+            );
+        }
+        env.create_dir_all(path)?;
+        let lock = env.lock(path, true)?;
+        let files = [CHECKPOINT_FILENAME, LOG_FILENAME];
+        if files.iter().any(|file| env.exists(&path.join(file))) {
+            return Err(Error::new(format!(
+                "{} already holds a database",
+                path.display()
+            )));
+        }
+        
+        copy_file(&*env, &checkpoint, &path.join(CHECKPOINT_FILENAME), None)?;
+        let log = backup.join(LOG_FILENAME);
+        if env.exists(&log) {
+            copy_file(&*env, &log, &path.join(LOG_FILENAME), None)?;
+        }
+        env.sync_dir(path)?;
+        let res = Self::open_locked(path, options, lock, target);
+        if res.is_err() {
+            // Leave path as it was so the restore can be retried
+            for file in &files {
+                let _ = env.delete(&path.join(file));
+            }
+        }
+        res
+    }
+}
+
+// Copy src, or its first len bytes, to a new file dst and fsync it
+fn copy_file(env: &dyn Env, src: &Path, dst: &Path, len: Option<u64>)
+    -> Result<(), Error>
+{
+    let mut src_file = env.open_read(src).map_err(|e| {
+        Error::new(format!("Cannot open {}: {}", src.display(), e))
+    })?;
+    let mut dst_file = env.create(dst)?;
+    match len {
+        Some(len) => io::copy(&mut src_file.take(len), &mut dst_file)?,
+        None => io::copy(&mut src_file, &mut dst_file)?,
+    };
+    dst_file.sync()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    
+    #[test]
+    fn backup_and_restore_while_writing() {
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-backup-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (db_dir, backup_dir) = (dir.join("db"), dir.join("backup"));
+        let restore_dir = dir.join("restore");
+        
+        let db = DB::open(&db_dir).unwrap();
+        for i in 0..100 {
+            db.put(format!("key{}", i), "1").unwrap();
+        }
+        // Keep writing while the backup is taken
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (db, stop) = (db.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut i = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    db.put(format!("other{}", i), "1").unwrap();
+                    i += 1;
+                }
+            })
+        };
+        db.backup_to(&backup_dir).unwrap();
+        stop.store(true, Ordering::SeqCst);
+        writer.join().unwrap();
+        db.put("key0", "2").unwrap();
+        assert!(db.backup_to(&backup_dir).is_err());
+        
+        let restored = DB::restore_from(&backup_dir, &restore_dir).unwrap();
+        assert_eq!(restored.get("key99").unwrap(), Some(b"1".to_vec()));
+        // Written after the backup
+        assert_eq!(restored.get("key0").unwrap(), Some(b"1".to_vec()));
+        drop(restored);
+        assert!(DB::restore_from(&backup_dir, &restore_dir).is_err());
+        
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn open_backup_read_only() {
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-backup-ro-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (db_dir, backup_dir) = (dir.join("db"), dir.join("backup"));
+        
+        let db = DB::open(&db_dir).unwrap();
+        db.put("foo", "1").unwrap();
+        db.backup_to(&backup_dir).unwrap();
+        drop(db);
+        
+        // The backup has no LOCK file
+        let backup = DB::open_read_only(&backup_dir).unwrap();
+        assert_eq!(backup.get("foo").unwrap(), Some(b"1".to_vec()));
+        assert!(DB::open_read_only(&backup_dir).is_ok());
+        assert!(DB::open(&backup_dir).is_err());
+        drop(backup);
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn restore_to_point_in_time() {
+        use crate::log::io::LogReader;
+        use crate::log::logentry::LogEntry;
+        use crate::types;
+        
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-pitr-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (db_dir, backup_dir) = (dir.join("db"), dir.join("backup"));
+        
+        let before_bad;
+        {
+            let db = DB::open(&db_dir).unwrap();
+            db.put("a", "1").unwrap();
+            db.backup_to(&backup_dir).unwrap();
+            before_bad = types::now();
+            thread::sleep(std::time::Duration::from_millis(1));
+            db.put("a", "bad").unwrap();
+            db.put("b", "1").unwrap();
+        }
+        // The log archived after the bad transaction
+        let log = db_dir.join(LOG_FILENAME);
+        fs::copy(&log, backup_dir.join(LOG_FILENAME)).unwrap();
+        let mut reader = LogReader::open(&log).unwrap();
+        let bad_xid = std::iter::from_fn(|| reader.read())
+            .find_map(|log| match log {
+                LogEntry::Update { xid, value: Some(value), .. }
+                    if value == b"bad" => Some(xid),
+                _ => None,
+            })
+            .unwrap();
+        
+        let targets = [
+            RecoveryTarget::BeforeXid(bad_xid),
+            RecoveryTarget::Time(before_bad),
+        ];
+        for (i, target) in targets.iter().enumerate() {
+            let restore_dir = dir.join(format!("restore{}", i));
+            let db = DB::restore_to(&backup_dir, &restore_dir, *target)
+                .unwrap();
+            assert_eq!(db.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(db.get("b").unwrap(), None);
+            db.put("c", "1").unwrap();
+            drop(db);
+            // Dropped for good, not replayed on the next open
+            let db = DB::open(&restore_dir).unwrap();
+            assert_eq!(db.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(db.get("c").unwrap(), Some(b"1".to_vec()));
+        }
+        
+        // The backup's checkpoint already has a=1
+        let restore_dir = dir.join("restore-too-early");
+        let target = RecoveryTarget::Time(0);
+        assert!(DB::restore_to(&backup_dir, &restore_dir, target).is_err());
+        assert!(DB::restore_from(&backup_dir, &restore_dir).is_ok());
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+/// A group of puts and deletes that `DB::write` commits atomically.
+///
+/// The batch is logged as a single transaction, one XBegin, an Update per
+/// operation and one XCommit, rather than a transaction per key. It's applied
+/// to live storage as a unit: readers see either none or all of it, and a
+/// checkpoint captures either none or all of it.
+///
+/// Operations are applied in the order they were added, so a later put or
+/// delete of the same key wins.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self { writes: Vec::new() }
+    }
+    
+    pub fn put<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>
+    {
+        let key = key.as_ref().to_vec();
+        self.writes.push((key, Some(value.as_ref().to_vec())));
+        self
+    }
+    
+    pub fn delete<K>(&mut self, key: K) -> &mut Self
+    where
+        K: AsRef<[u8]>
+    {
+        self.writes.push((key.as_ref().to_vec(), None));
+        self
+    }
+    
+    /// Number of operations in the batch
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+    
+    pub fn clear(&mut self) {
+        self.writes.clear();
+    }
+    
+    /// Keep only the first len operations
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.writes.truncate(len);
+    }
+    
+    /// The last write to key in the batch, None if there is none
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        self.writes.iter()
+            .rev()
+            .find(|(k, _)| k.as_slice() == key)
+            .map(|(_, value)| value.as_deref())
+    }
+    
+    pub(crate) fn writes(&self) -> Vec<(&[u8], Option<&[u8]>)> {
+        self.writes.iter()
+            .map(|(key, value)| (key.as_slice(), value.as_deref()))
+            .collect()
+    }
+}
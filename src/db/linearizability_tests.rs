@@ -0,0 +1,300 @@
+//! Run random operations against one database from several threads while
+//! checkpoints are taken, and check that what the threads saw is
+//! linearizable.
+//!
+//! Every operation is recorded along with when it was called and when it
+//! returned. The history is linearizable if each operation can be given an
+//! instant between the two such that, in that order, the operations are
+//! valid for a plain map starting out empty. The search is the one of Wing &
+//! Gong with Lowe's memoization, as done by Knossos and Porcupine: pick any
+//! operation called before the first pending return as the next one, and
+//! never look at the same set of linearized operations with the same map
+//! twice. Batches make every key depend on every other, so the history is
+//! checked as a whole rather than per key.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::db::{DBOptions, DBRef, WriteBatch, DB};
+use crate::env::MemEnv;
+
+const KEYS: usize = 3;
+const THREADS: u64 = 4;
+// Keeps a round within the 128 operations a u128 can keep track of
+const OPS_PER_THREAD: u64 = 28;
+const ROUNDS: u64 = 30;
+
+// Value of every key, values are unique per write
+type State = [Option<u64>; KEYS];
+
+#[derive(Clone, Debug)]
+enum Op {
+    Get { key: usize, value: Option<u64> },
+    Put { key: usize, value: u64 },
+    Delete { key: usize },
+    Cas {
+        key: usize,
+        expected: Option<u64>,
+        new: Option<u64>,
+        swapped: bool,
+    },
+    Batch(Vec<(usize, Option<u64>)>),
+}
+
+impl Op {
+    // The state after op, or None if its outcome is impossible in state
+    fn apply(&self, state: &State) -> Option<State> {
+        let mut next = *state;
+        match self {
+            Op::Get { key, value } => {
+                if state[*key] != *value {
+                    return None;
+                }
+            }
+            Op::Put { key, value } => next[*key] = Some(*value),
+            Op::Delete { key } => next[*key] = None,
+            Op::Cas { key, expected, new, swapped } => {
+                if (state[*key] == *expected) != *swapped {
+                    return None;
+                }
+                if *swapped {
+                    next[*key] = *new;
+                }
+            }
+            Op::Batch(writes) => {
+                for (key, value) in writes {
+                    next[*key] = *value;
+                }
+            }
+        }
+        Some(next)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Event {
+    op: Op,
+    call: u64,
+    ret: u64,
+}
+
+fn linearizable(history: &[Event]) -> bool {
+    assert!(history.len() <= 128);
+    let mut visited = HashSet::new();
+    search(history, 0, [None; KEYS], &mut visited)
+}
+
+// Whether the operations not in done can be linearized from state on
+fn search(
+    history: &[Event],
+    done: u128,
+    state: State,
+    visited: &mut HashSet<(u128, State)>,
+) -> bool {
+    let pending: Vec<usize> = (0..history.len())
+        .filter(|i| done & 1 << i == 0)
+        .collect();
+    let first_ret = match pending.iter().map(|&i| history[i].ret).min() {
+        Some(ret) => ret,
+        None => return true,
+    };
+    for &i in &pending {
+        // Anything called later has to come after the operation returning
+        // first
+        if history[i].call > first_ret {
+            continue;
+        }
+        let next = match history[i].op.apply(&state) {
+            Some(next) => next,
+            None => continue,
+        };
+        let done = done | 1 << i;
+        if visited.insert((done, next))
+            && search(history, done, next, visited)
+        {
+            return true;
+        }
+    }
+    false
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key{}", i).into_bytes()
+}
+
+fn encode(value: Option<u64>) -> Option<Vec<u8>> {
+    value.map(|value| value.to_string().into_bytes())
+}
+
+fn decode(value: Option<Vec<u8>>) -> Option<u64> {
+    value.map(|value| String::from_utf8(value).unwrap().parse().unwrap())
+}
+
+// SplitMix64, good enough to pick operations
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        (z ^ (z >> 31)) % n
+    }
+}
+
+// Run a random operation on db and return it with its outcome. seen holds
+// the last value the thread saw for each key, which CAS expects.
+fn run_op(db: &DB, rng: &mut Rng, next_value: &AtomicU64, seen: &mut State)
+    -> Op
+{
+    let k = rng.below(KEYS as u64) as usize;
+    let value = || next_value.fetch_add(1, Ordering::SeqCst);
+    match rng.below(6) {
+        0 | 1 => {
+            let v = decode(db.get(key(k)).unwrap());
+            seen[k] = v;
+            Op::Get { key: k, value: v }
+        }
+        2 => {
+            let v = value();
+            db.put(key(k), v.to_string()).unwrap();
+            Op::Put { key: k, value: v }
+        }
+        3 => {
+            db.delete(key(k)).unwrap();
+            Op::Delete { key: k }
+        }
+        4 => {
+            let new = if rng.below(4) == 0 { None } else { Some(value()) };
+            let (expected_bytes, new_bytes) = (encode(seen[k]), encode(new));
+            let swapped = db.compare_and_swap(
+                key(k),
+                expected_bytes.as_deref(),
+                new_bytes.as_deref(),
+            ).unwrap();
+            let op = Op::Cas { key: k, expected: seen[k], new, swapped };
+            if swapped {
+                seen[k] = new;
+            }
+            op
+        }
+        _ => {
+            let mut batch = WriteBatch::new();
+            let mut writes = vec![];
+            for k in 0..KEYS {
+                let v = match rng.below(3) {
+                    0 => continue,
+                    1 => None,
+                    _ => Some(value()),
+                };
+                match encode(v) {
+                    Some(bytes) => batch.put(key(k), bytes),
+                    None => batch.delete(key(k)),
+                };
+                writes.push((k, v));
+            }
+            db.write(&batch).unwrap();
+            Op::Batch(writes)
+        }
+    }
+}
+
+// One round of THREADS clients against a fresh database, while another
+// thread keeps taking checkpoints
+fn record_history(round: u64) -> Vec<Event> {
+    let options = DBOptions {
+        env: Arc::new(MemEnv::new()),
+        ..DBOptions::default()
+    };
+    let db: DBRef = DB::open_with_options("/thorkv", options).unwrap();
+    let clock = AtomicU64::new(0);
+    let next_value = AtomicU64::new(0);
+    let history = Mutex::new(vec![]);
+    let stop = AtomicBool::new(false);
+    thread::scope(|s| {
+        s.spawn(|| {
+            while !stop.load(Ordering::SeqCst) {
+                db.checkpoint().unwrap();
+            }
+        });
+        let clients: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let (db, clock) = (&db, &clock);
+                let (next_value, history) = (&next_value, &history);
+                s.spawn(move || {
+                    let mut rng = Rng(round * THREADS + t);
+                    let mut seen = [None; KEYS];
+                    for _ in 0..OPS_PER_THREAD {
+                        let call = clock.fetch_add(1, Ordering::SeqCst);
+                        let op = run_op(db, &mut rng, next_value, &mut seen);
+                        let ret = clock.fetch_add(1, Ordering::SeqCst);
+                        history.lock().unwrap().push(Event { op, call, ret });
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        stop.store(true, Ordering::SeqCst);
+    });
+    history.into_inner().unwrap()
+}
+
+#[test]
+fn concurrent_operations_are_linearizable() {
+    for round in 0..ROUNDS {
+        let history = record_history(round);
+        assert!(
+            linearizable(&history),
+            "round {}: history is not linearizable: {:#?}",
+            round,
+            history
+        );
+    }
+}
+
+#[test]
+fn checker_rejects_stale_reads() {
+    let event = |op, call, ret| Event { op, call, ret };
+    let put = |value| Op::Put { key: 0, value };
+    let get = |value| Op::Get { key: 0, value };
+    
+    // The get overlaps the second put, it may see either value
+    let history = vec![
+        event(put(1), 0, 1),
+        event(put(2), 2, 5),
+        event(get(Some(1)), 3, 4),
+    ];
+    assert!(linearizable(&history));
+    
+    // The second put returned before the get was called
+    let history = vec![
+        event(put(1), 0, 1),
+        event(put(2), 2, 3),
+        event(get(Some(1)), 4, 5),
+    ];
+    assert!(!linearizable(&history));
+    
+    // Half a batch
+    let batch = Op::Batch(vec![(0, Some(1)), (1, Some(1))]);
+    let history = vec![
+        event(batch, 0, 3),
+        event(get(Some(1)), 1, 4),
+        event(Op::Get { key: 1, value: None }, 5, 6),
+    ];
+    assert!(!linearizable(&history));
+    
+    // Two CAS from the same value can't both succeed
+    let cas = |new| Op::Cas {
+        key: 0,
+        expected: None,
+        new: Some(new),
+        swapped: true,
+    };
+    let history = vec![event(cas(1), 0, 2), event(cas(2), 1, 3)];
+    assert!(!linearizable(&history));
+}
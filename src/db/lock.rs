@@ -0,0 +1,134 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use fs2::FileExt;
+
+use crate::constants::LOCK_FILENAME;
+use crate::types::Error;
+
+/// Advisory lock on a database directory.
+///
+/// A read-write instance takes an exclusive lock on the LOCK file and records
+/// its PID in it, so a second instance opening the same directory fails
+/// instead of appending to the same WAL and overwriting the checkpoint.
+/// Read-only instances take a shared lock: any number of them may inspect a
+/// directory at the same time, but not while a writer holds it.
+///
+/// A directory that never had a writer, such as a backup, may have no LOCK
+/// file. A reader creates it, unless the directory is read-only: then no
+/// writer can use it either and the reader goes without a lock.
+///
+/// The lock is released when the DirLock is dropped (or when the process
+/// dies, since it's an flock).
+pub struct DirLock {
+    // None for a reader of a read-only directory without a LOCK file
+    file: Option<File>,
+}
+
+impl DirLock {
+    pub fn exclusive(dir: &Path) -> Result<Self, Error> {
+        let mut file = Self::open_lock_file(dir, true)
+            .map_err(|e| Self::cannot_open(dir, e))?;
+        if file.try_lock_exclusive().is_err() {
+            return Err(Self::contended(dir, &mut file));
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", std::process::id())?;
+        file.sync_data()?;
+        Ok(Self { file: Some(file) })
+    }
+
+    pub fn shared(dir: &Path) -> Result<Self, Error> {
+        let file = match Self::open_lock_file(dir, false) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                match Self::open_lock_file(dir, true) {
+                    Err(e) if is_read_only(&e) => {
+                        return Ok(Self { file: None });
+                    }
+                    res => res,
+                }
+            }
+            res => res,
+        };
+        let mut file = file.map_err(|e| Self::cannot_open(dir, e))?;
+        if file.try_lock_shared().is_err() {
+            return Err(Self::contended(dir, &mut file));
+        }
+        Ok(Self { file: Some(file) })
+    }
+
+    fn open_lock_file(dir: &Path, create: bool) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(create)
+            .create(create)
+            .open(dir.join(LOCK_FILENAME))
+    }
+
+    fn cannot_open(dir: &Path, err: io::Error) -> Error {
+        let path = dir.join(LOCK_FILENAME);
+        Error::new(format!("Cannot open {}: {}", path.display(), err))
+    }
+
+    fn contended(dir: &Path, file: &mut File) -> Error {
+        let mut pid = String::new();
+        let _ = file.read_to_string(&mut pid);
+        let pid = pid.trim();
+        if pid.is_empty() {
+            Error::new(format!(
+                "Database {} is locked by another instance",
+                dir.display()
+            ))
+        } else {
+            Error::new(format!(
+                "Database {} is locked by another instance (pid {})",
+                dir.display(),
+                pid
+            ))
+        }
+    }
+}
+
+fn is_read_only(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem
+    )
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        if let Some(file) = &self.file {
+            let _ = file.unlock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusive_lock_excludes_other_instances() {
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        {
+            let _lock = DirLock::exclusive(&dir).unwrap();
+            let err = DirLock::exclusive(&dir).err().unwrap();
+            let pid = std::process::id().to_string();
+            assert!(err.to_string().contains(&pid));
+            assert!(DirLock::shared(&dir).is_err());
+        }
+
+        // Released on drop; readers can now share the directory
+        let _r1 = DirLock::shared(&dir).unwrap();
+        let _r2 = DirLock::shared(&dir).unwrap();
+        assert!(DirLock::exclusive(&dir).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,123 +1,946 @@
-/// DB is the main interface to ThorKV.
-///
-/// There are two storages, one is for live version and the other is for 
-/// stable version. We expect the size of the stable version storage remains
-/// small since it's content are removed when the record is written to disk.
-///
-/// We also keep track of a map from a "key" to whether there is a stable 
-/// version for that key.
+//! DB is the main interface to ThorKV.
+//!
+//! There are two storages, one is for live version and the other is for
+//! stable version. We expect the size of the stable version storage remains
+//! small since it's content are removed when the record is written to disk.
+//!
+//! We also keep track of a map from a "key" to whether there is a stable
+//! version for that key.
+//!
+//! A database lives in its own directory which holds the WAL, the checkpoint
+//! and a LOCK file. Only one read-write instance may have a directory open at
+//! a time, see `lock::DirLock`. Its files are accessed through the
+//! `env::Env` in DBOptions, the local filesystem by default.
+//!
+//! Every write is a transaction that is logged as a contiguous group of
+//! XBegin, Update..., XCommit entries and applied to live storage once it's
+//! in the log. Commits are serialized so the order in memory matches the
+//! order in the log.
+//!
+//! The DB runs its background work (log flusher, checkpointer) on threads
+//! it owns, so it can be used from plain synchronous code as well as from
+//! any async runtime. The `*_async` methods never block on disk I/O.
 
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
 
-use lockfree::set::Set;
+use tracing::{info, warn};
 
-use crate::checkpoint::{Checkpointer, start_checkpointer};
-use crate::checkpoint::io::CheckpointWriter;
-use crate::storage::KeyValueStorage;
+use crate::checkpoint::{Checkpointer, run_checkpointer, start_checkpointer};
+use crate::checkpoint::io::{CheckpointHeader, CheckpointWriter};
+use crate::constants::{CHECKPOINT_FILENAME, LOG_FILENAME};
+use crate::db::mvcc::VersionChains;
+use crate::db::phase::PhaseLock;
+use crate::db::versions::{StorageRef, Versions};
+use crate::env::EnvLock;
+use crate::log::LogManager;
+use crate::log::logentry::{LogEntry, LogicalOperation};
+use crate::metrics::Metrics;
+use crate::recovery;
 use crate::storage::lfmap::LFMapStorage;
 use crate::transaction::table::{TransactionTable, TransactionTableRef};
-use crate::types::{CheckpointPhase, Error, Xid};
+use crate::types::{self, CheckpointPhase, Error, Lsn, Xid};
+
+mod backup;
+mod batch;
+mod mvcc;
+mod options;
+mod phase;
+mod snapshot;
+mod stats;
+mod transaction;
+mod versions;
+
+#[cfg(test)]
+mod crash_tests;
+#[cfg(test)]
+mod linearizability_tests;
+
+pub mod lock;
+
+pub use batch::WriteBatch;
+pub use options::{Concurrency, DBOptions, LogFormat};
+pub use snapshot::Snapshot;
+pub use stats::Stats;
+pub use transaction::{IsolationLevel, Transaction};
+pub use crate::recovery::RecoveryTarget;
+pub use crate::transaction::table::{TransactionInfo, TransactionState};
 
 pub type DBRef = Arc<DB>;
 
 pub struct DB {
+    path: PathBuf,
+    options: DBOptions,
+    read_only: bool,
+    _lock: EnvLock,
     xtable: TransactionTableRef,
-    phase: RwLock<CheckpointPhase>,
-    live_storage: Arc<dyn KeyValueStorage + Send + Sync>,
-    stable_storage: Arc<dyn KeyValueStorage + Send + Sync>,
-    stable_keys: Set<Vec<u8>>,
-    // We need a graveyard to keep the set of keys that has been deleted
-    // on the live version but still alive on the stable version.
-    graveyard: Set<Vec<u8>>,
+    phase: PhaseLock,
+    versions: Versions,
+    // Some with Concurrency::Mvcc
+    mvcc: Option<VersionChains>,
+    // None when opened read-only
+    log: Option<LogManager>,
+    // Held while a transaction is logged and applied
+    write_lock: Mutex<()>,
+    // Position in the log of the running checkpoint's point of consistency
+    checkpoint_lsn: AtomicU64,
+    closed: AtomicBool,
+    checkpointer: Mutex<Option<(Arc<Checkpointer>, JoinHandle<()>)>>,
+    // Held while a checkpoint is taken, and by ingest
+    checkpoint_lock: Mutex<()>,
+    metrics: Arc<Metrics>,
+    // When the current checkpoint phase was entered
+    phase_started: Mutex<Instant>,
 }
 
 impl DB {
-    pub fn open(path: &str) -> DBRef {
-        // TODO: Start recovery
-        
-        let xtable = Arc::new(TransactionTable::new());
+    /// Open the database stored in the given directory, creating the
+    /// directory if it doesn't exist yet.
+    ///
+    /// Fails if another instance already has the directory open.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<DBRef, Error> {
+        Self::open_with_options(path, DBOptions::default())
+    }
+    
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: DBOptions)
+        -> Result<DBRef, Error>
+    {
+        let path = path.as_ref();
+        options.env.create_dir_all(path).map_err(|e| {
+            Error::new(format!("Cannot create {}: {}", path.display(), e))
+        })?;
+        let lock = options.env.lock(path, true)?;
+        Self::open_locked(path, options, lock, None)
+    }
+    
+    // Recover up to target, dropping whatever is logged after it
+    fn open_locked(
+        path: &Path,
+        options: DBOptions,
+        lock: EnvLock,
+        target: Option<RecoveryTarget>,
+    ) -> Result<DBRef, Error> {
+        let env = &*options.env;
+        let live_storage = Arc::new(LFMapStorage::new());
+        let lsn = recovery::recover(env, path, &*live_storage, target)?;
+        recovery::truncate_log(env, path, lsn)?;
+        let metrics = Arc::new(Metrics::new());
+        let log_path = path.join(LOG_FILENAME);
+        let log = LogManager::open(env, &log_path, lsn, metrics.clone())?;
         
-        let db = Arc::new(
-            Self {
-                xtable: xtable.clone(),
-                phase: RwLock::new(CheckpointPhase::REST),
-                live_storage: Arc::new(LFMapStorage::new()),
-                stable_storage: Arc::new(LFMapStorage::new()),
-                stable_keys: Set::new(),
-                graveyard: Set::new(),
-            }
+        let db = Self::with_lock(
+            path, options, lock, live_storage, Some(log), metrics,
         );
         
         // Start checkpointer
-        let checkpointer = Arc::new(Checkpointer::new(db.clone(), xtable));
-        start_checkpointer(checkpointer);
+        let checkpointer = Arc::new(Checkpointer::new(Arc::downgrade(&db)));
+        let handle = start_checkpointer(checkpointer.clone())?;
+        *db.checkpointer.lock().unwrap() = Some((checkpointer, handle));
+        info!(path = %path.display(), lsn, "Opened database");
         
-        db
+        Ok(db)
+    }
+    
+    /// Open an existing database for inspection.
+    ///
+    /// Any number of read-only instances may share a directory, but not with
+    /// a read-write instance. Writes are rejected and no checkpoint is taken.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<DBRef, Error> {
+        Self::open_read_only_with_options(path, DBOptions::default())
+    }
+    
+    pub fn open_read_only_with_options<P: AsRef<Path>>(
+        path: P,
+        options: DBOptions,
+    ) -> Result<DBRef, Error> {
+        let path = path.as_ref();
+        if !options.env.exists(path) {
+            return Err(
+                Error::new(format!("No database at {}", path.display()))
+            );
+        }
+        let lock = options.env.lock(path, false)?;
+        
+        let live_storage = Arc::new(LFMapStorage::new());
+        recovery::recover(&*options.env, path, &*live_storage, None)?;
+        
+        let metrics = Arc::new(Metrics::new());
+        Ok(Self::with_lock(path, options, lock, live_storage, None, metrics))
+    }
+    
+    fn with_lock(
+        path: &Path,
+        options: DBOptions,
+        lock: EnvLock,
+        live_storage: StorageRef,
+        log: Option<LogManager>,
+        metrics: Arc<Metrics>,
+    ) -> DBRef {
+        let xtable = Arc::new(TransactionTable::new());
+        let mvcc = match options.concurrency {
+            Concurrency::Locking => None,
+            Concurrency::Mvcc => Some(VersionChains::new()),
+        };
+        
+        Arc::new(
+            Self {
+                path: path.to_path_buf(),
+                options,
+                read_only: log.is_none(),
+                _lock: lock,
+                xtable,
+                phase: PhaseLock::new(),
+                versions: Versions::new(
+                    live_storage,
+                    Arc::new(LFMapStorage::new()),
+                ),
+                mvcc,
+                log,
+                write_lock: Mutex::new(()),
+                checkpoint_lsn: AtomicU64::new(0),
+                closed: AtomicBool::new(false),
+                checkpointer: Mutex::new(None),
+                checkpoint_lock: Mutex::new(()),
+                metrics,
+                phase_started: Mutex::new(Instant::now()),
+            }
+        )
+    }
+    
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
     }
     
     pub fn get<K>(&self, key: K) -> Result<Option<Vec<u8>>, Error>
     where K: AsRef<[u8]>
     {
-        let v = self.live_storage.get(key.as_ref());
+        let start = Instant::now();
+        let v = self.versions.get(key.as_ref());
+        self.metrics.get_latency.observe_duration(start.elapsed());
         Ok(v)
     }
-        
+    
     pub fn put<K, V>(&self, key: K, value: V) -> Result<(), Error>
-    where 
+    where
         K: AsRef<[u8]>,
         V: AsRef<[u8]>
     {
-        // TODO
-        Ok(())
+        let start = Instant::now();
+        let res = self.write_one(key.as_ref(), Some(value.as_ref()));
+        self.metrics.put_latency.observe_duration(start.elapsed());
+        res
     }
-        
+    
     pub fn delete<K>(&self, key: K) -> Result<(), Error>
     where
         K: AsRef<[u8]>
     {
+        let start = Instant::now();
+        let res = self.write_one(key.as_ref(), None);
+        self.metrics.delete_latency.observe_duration(start.elapsed());
+        res
+    }
+    
+    /// Atomically apply a batch of puts and deletes, see `WriteBatch`.
+    pub fn write(&self, batch: &WriteBatch) -> Result<(), Error> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let res = self.commit(&batch.writes());
+        self.metrics.write_latency.observe_duration(start.elapsed());
+        res
+    }
+    
+    /// Atomically replace the value of key with new if it currently is
+    /// expected, None standing for an absent key on both sides.
+    ///
+    /// Returns whether the swap happened. Nothing is logged when it doesn't.
+    pub fn compare_and_swap<K>(
+        &self,
+        key: K,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, Error>
+    where
+        K: AsRef<[u8]>
+    {
+        self.check_writable()?;
+        let key = key.as_ref();
+        let start = Instant::now();
+        let xid = XidGuard::begin(&self.xtable);
+        let live = self.versions.live();
+        let precondition = || live.get(key).as_deref() == expected;
+        let writes = [(key, new)];
+        let res = match self.log_and_apply_if(xid.xid, &writes, precondition) {
+            Ok(Some(lsn)) => {
+                self.log.as_ref().unwrap().wait_durable(lsn).map(|_| true)
+            }
+            Ok(None) => Ok(false),
+            Err(err) => Err(err),
+        };
+        drop(xid);
+        self.metrics.write_latency.observe_duration(start.elapsed());
+        res
+    }
+    
+    /// Same as `get`. Reads never touch the disk, this exists so async
+    /// callers can use one style throughout.
+    pub async fn get_async<K>(&self, key: K) -> Result<Option<Vec<u8>>, Error>
+    where K: AsRef<[u8]>
+    {
+        self.get(key)
+    }
+    
+    /// Like `put`, but waits for the log to be durable without blocking the
+    /// calling thread.
+    pub async fn put_async<K, V>(&self, key: K, value: V) -> Result<(), Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>
+    {
+        let start = Instant::now();
+        let writes = [(key.as_ref(), Some(value.as_ref()))];
+        let res = self.commit_async(&writes).await;
+        self.metrics.put_latency.observe_duration(start.elapsed());
+        res
+    }
+    
+    /// Like `delete`, but waits for the log to be durable without blocking
+    /// the calling thread.
+    pub async fn delete_async<K>(&self, key: K) -> Result<(), Error>
+    where
+        K: AsRef<[u8]>
+    {
+        let start = Instant::now();
+        let res = self.commit_async(&[(key.as_ref(), None)]).await;
+        self.metrics.delete_latency.observe_duration(start.elapsed());
+        res
+    }
+    
+    /// Like `write`, but waits for the log to be durable without blocking
+    /// the calling thread.
+    pub async fn write_async(&self, batch: &WriteBatch) -> Result<(), Error> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let res = self.commit_async(&batch.writes()).await;
+        self.metrics.write_latency.observe_duration(start.elapsed());
+        res
+    }
+    
+    /// Bulk load key-value pairs, overwriting existing keys.
+    ///
+    /// The pairs are applied like commits but aren't logged. A checkpoint is
+    /// taken once all of them are loaded, which is when they become durable;
+    /// no other checkpoint runs in the meantime, so a crash before ingest
+    /// returns loses all of them. Readers may see part of the data while it
+    /// loads, snapshots taken and transactions begun before it see none.
+    ///
+    /// Stops at the first Err from records and returns it. The pairs loaded
+    /// up to that point stay, and are persisted by the next checkpoint.
+    ///
+    /// Returns the number of pairs loaded.
+    pub fn ingest<I, K, V>(&self, records: I) -> Result<u64, Error>
+    where
+        I: IntoIterator<Item = Result<(K, V), Error>>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>
+    {
+        self.check_writable()?;
+        let _checkpoint_guard = self.checkpoint_lock.lock().unwrap();
+        // Checkpoints are excluded so the phase stays REST
+        let mut count = 0;
+        for record in records {
+            let (key, value) = record?;
+            let writes = [(key.as_ref(), Some(value.as_ref()))];
+            let _write_guard = self.write_lock.lock().unwrap();
+            self.record_versions(&writes);
+            self.versions.apply_all(CheckpointPhase::REST, &writes);
+            count += 1;
+        }
+        run_checkpointer(self, &self.xtable)?;
+        Ok(count)
+    }
+    
+    /// Take a checkpoint now rather than waiting for the checkpointer.
+    pub fn checkpoint(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        let _checkpoint_guard = self.checkpoint_lock.lock().unwrap();
+        run_checkpointer(self, &self.xtable)
+    }
+    
+    fn write_one(&self, key: &[u8], value: Option<&[u8]>) -> Result<(), Error> {
+        self.commit(&[(key, value)])
+    }
+    
+    fn commit(&self, writes: &[(&[u8], Option<&[u8]>)]) -> Result<(), Error> {
+        self.check_writable()?;
+        let xid = XidGuard::begin(&self.xtable);
+        self.log_and_apply(xid.xid, writes)
+            .and_then(|lsn| self.log.as_ref().unwrap().wait_durable(lsn))
+    }
+    
+    async fn commit_async(&self, writes: &[(&[u8], Option<&[u8]>)])
+        -> Result<(), Error>
+    {
+        self.check_writable()?;
+        // The future may be dropped while it waits for the log, the guard
+        // still ends the xid then
+        let xid = XidGuard::begin(&self.xtable);
+        let lsn = self.log_and_apply(xid.xid, writes)?;
+        self.log.as_ref().unwrap().durable(lsn).await
+    }
+    
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::new("Database is opened read-only"));
+        }
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Error::new("Database is closed"));
+        }
         Ok(())
     }
     
-    pub fn set_phase(&self, phase: CheckpointPhase) -> Xid {
-        // TODO: There can be a race condition between the time we fetch xid 
-        // until the time we set phase. Could be a problem???????
-        let mut phase_guard = self.phase.write().unwrap();
-        *phase_guard = phase;
-        let xid = self.xtable.next_xid();
-        xid
+    /// Log the writes of transaction xid and apply them to live storage.
+    ///
+    /// Returns the LSN the caller has to wait for before reporting the
+    /// commit as durable.
+    fn log_and_apply(&self, xid: Xid, writes: &[(&[u8], Option<&[u8]>)])
+        -> Result<Lsn, Error>
+    {
+        let lsn = self.log_and_apply_if(xid, writes, || true)?;
+        Ok(lsn.unwrap())
+    }
+    
+    /// Like log_and_apply, but only if precondition holds once no other
+    /// transaction can commit. Returns None, having done nothing, if it
+    /// doesn't.
+    fn log_and_apply_if<F>(
+        &self,
+        xid: Xid,
+        writes: &[(&[u8], Option<&[u8]>)],
+        precondition: F,
+    ) -> Result<Option<Lsn>, Error>
+    where
+        F: FnOnce() -> bool
+    {
+        let log = self.log.as_ref().unwrap();
+        let lsn = {
+            let _write_guard = self.write_lock.lock().unwrap();
+            if !precondition() {
+                return Ok(None);
+            }
+            let phase = self.phase.read();
+            
+            let mut logs = Vec::with_capacity(writes.len() + 2);
+            logs.push(LogEntry::XBegin { xid });
+            // The previous value of a key written more than once is the one
+            // written earlier in the same transaction
+            let mut written: HashMap<&[u8], Option<&[u8]>> = HashMap::new();
+            for (key, value) in writes {
+                let key_vec = key.to_vec();
+                let log = match self.options.log_format {
+                    LogFormat::Physical => {
+                        let previous_value = match written.insert(key, *value) {
+                            Some(previous) => previous.map(|v| v.to_vec()),
+                            None => self.versions.live().get(key),
+                        };
+                        LogEntry::Update {
+                            xid,
+                            key: key_vec,
+                            value: value.map(|v| v.to_vec()),
+                            previous_value,
+                        }
+                    }
+                    LogFormat::RedoOnly => {
+                        let op = match value {
+                            Some(value) => LogicalOperation::Set {
+                                key: key_vec,
+                                value: value.to_vec(),
+                            },
+                            None => LogicalOperation::Delete { key: key_vec },
+                        };
+                        LogEntry::Operation { xid, op }
+                    }
+                };
+                logs.push(log);
+            }
+            let timestamp = types::now();
+            logs.push(LogEntry::XCommit { xid, timestamp });
+            let lsn = log.append(&logs)?;
+            
+            self.record_versions(writes);
+            self.versions.apply_all(*phase, writes);
+            lsn
+        };
+        Ok(Some(lsn))
+    }
+    
+    // Keep the versions writes replace for the MVCC transactions that began
+    // before them, under a new xid. Called with write_lock held, right
+    // before the writes are applied.
+    fn record_versions(&self, writes: &[(&[u8], Option<&[u8]>)]) {
+        if let Some(chains) = &self.mvcc {
+            let horizon = mvcc::horizon(&self.xtable);
+            let live = &**self.versions.live();
+            chains.record(live, self.xtable.allocate(), writes, horizon);
+        }
+    }
+    
+    pub fn set_phase(&self, phase: CheckpointPhase) -> Result<Xid, Error> {
+        self.phase.switch(phase, &self.xtable, |old_phase| {
+            if let Some(log) = &self.log {
+                let lsn = log.append(&[LogEntry::CPhase(phase)])?;
+                if phase == CheckpointPhase::RESOLVE {
+                    self.checkpoint_lsn.store(lsn, Ordering::SeqCst);
+                }
+            }
+            let mut phase_started = self.phase_started.lock().unwrap();
+            if let Some(histogram) = self.metrics.phase_duration(old_phase) {
+                histogram.observe_duration(phase_started.elapsed());
+            }
+            *phase_started = Instant::now();
+            Ok(())
+        })
+    }
+    
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.metrics
     }
     
     pub fn current_phase(&self) -> CheckpointPhase {
-        let phase = self.phase.read().unwrap();
+        let phase = self.phase.read();
         *phase
     }
     
     // This should iterate over all key values and save it to disk.
-    // Setting the stable_status and cleaning up stable record along the
-    // way.
-    pub fn save_checkpoint(&self) {
-        let mut writer = CheckpointWriter::new();
-        let keys = self.live_storage.keys();
-        for key in keys {
-            let value;
-            if self.stable_keys.contains(&key) {
-                value = self.stable_storage.get(&key);
-            } else {
-                value = self.live_storage.get(&key);
+    pub fn save_checkpoint(&self) -> Result<(), Error> {
+        let lsn: Lsn = self.checkpoint_lsn.load(Ordering::SeqCst);
+        // The checkpoint must not point past the durable end of the log
+        if let Some(log) = &self.log {
+            log.wait_durable(lsn)?;
+        }
+        
+        let filepath = self.path.join(CHECKPOINT_FILENAME);
+        let mut writer = CheckpointWriter::create_with_env(
+            self.options.env.clone(),
+            &filepath,
+            CheckpointHeader { lsn },
+        )?;
+        self.versions.capture(|key, value| writer.append(key, value))?;
+        writer.commit()
+    }
+    
+    /// Abort the transactions that ran past their timeout, logging XAbort
+    /// for each. Their writes were never applied or logged, there is
+    /// nothing else to roll back.
+    pub(crate) fn abort_expired(&self) {
+        for xid in self.xtable.abort_expired(Instant::now()) {
+            warn!(xid, "Aborted a transaction that ran past its timeout");
+            if let Some(log) = &self.log {
+                if let Err(err) = log.append(&[LogEntry::XAbort { xid }]) {
+                    warn!(xid, %err, "Failed to log an abort");
+                }
             }
-            if value.is_some() {
-                let value = value.unwrap();
-                writer.append(&key, &value);
+        }
+    }
+    
+    /// The transactions begun with `begin` that haven't ended yet, along
+    /// with when they began and their state
+    pub fn transactions(&self) -> Vec<TransactionInfo> {
+        self.xtable.tracked()
+    }
+    
+    pub fn post_checkpoint(&self) {
+        self.versions.clear_stable();
+        // Commits only collect the chains they write to
+        if let Some(chains) = &self.mvcc {
+            chains.collect(mvcc::horizon(&self.xtable));
+        }
+    }
+    
+    /// Shut the database down.
+    ///
+    /// New writes are rejected, the checkpointer is stopped and everything
+    /// still queued for the log is written and fsynced. Dropping the last
+    /// DBRef does the same.
+    pub fn close(&self) -> Result<(), Error> {
+        self.shutdown(false)
+    }
+    
+    /// Like `close`, but take a final checkpoint before shutting down the
+    /// log so the next open doesn't have to replay it.
+    pub fn close_with_checkpoint(&self) -> Result<(), Error> {
+        self.shutdown(true)
+    }
+    
+    fn shutdown(&self, final_checkpoint: bool) -> Result<(), Error> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        
+        if let Some((checkpointer, handle)) =
+            self.checkpointer.lock().unwrap().take()
+        {
+            checkpointer.stop();
+            // The checkpointer thread drops the last DBRef if the DB goes out
+            // of scope while a checkpoint is running, it can't join itself.
+            if handle.thread().id() != thread::current().id() {
+                handle.join()
+                    .map_err(|_| Error::new("Checkpointer thread panicked"))?;
             }
+        }
+        
+        // Let commits that got past check_writable finish
+        drop(self.write_lock.lock().unwrap());
+        
+        let mut res = Ok(());
+        if final_checkpoint && !self.read_only {
+            let _checkpoint_guard = self.checkpoint_lock.lock().unwrap();
+            res = run_checkpointer(self, &self.xtable);
+        }
+        if let Some(log) = &self.log {
+            log.close()?;
+        }
+        res
+    }
+}
+
+impl Drop for DB {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown(false) {
+            warn!(%err, "Failed to close the database");
+        }
+    }
+}
+
+// Ends the xid of a commit when dropped. Checkpoints wait for every xid
+// that began before them to end, so one that is never ended blocks them
+// forever.
+struct XidGuard<'a> {
+    xtable: &'a TransactionTable,
+    xid: Xid,
+}
+
+impl<'a> XidGuard<'a> {
+    fn begin(xtable: &'a TransactionTable) -> Self {
+        Self { xtable, xid: xtable.begin() }
+    }
+}
+
+impl Drop for XidGuard<'_> {
+    fn drop(&mut self) {
+        self.xtable.end(&self.xid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::env::{Env, MemEnv};
+    
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-db-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+    
+    #[test]
+    fn reopen_after_close() {
+        let dir = test_dir("reopen");
+        
+        {
+            let db = DB::open(&dir).unwrap();
+            db.put("foo", "1").unwrap();
+            db.put("bar", "2").unwrap();
+            db.close_with_checkpoint().unwrap();
+            assert!(db.put("baz", "3").is_err());
+        }
+        {
+            // Recovered from the checkpoint, plus the log written after it
+            let db = DB::open(&dir).unwrap();
+            assert_eq!(db.get("foo").unwrap(), Some(b"1".to_vec()));
+            db.put("foo", "3").unwrap();
+            db.delete("bar").unwrap();
+        }
+        {
+            let db = DB::open_read_only(&dir).unwrap();
+            assert_eq!(db.get("foo").unwrap(), Some(b"3".to_vec()));
+            assert_eq!(db.get("bar").unwrap(), None);
+            assert!(db.put("foo", "4").is_err());
+        }
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn corrupt_log_entry_fails_open() {
+        use crate::util::serde::CURRENT_VERSION;
+        
+        let dir = test_dir("corrupt");
+        let log_path = dir.join(LOG_FILENAME);
+        DB::open(&dir).unwrap().put("foo", "1").unwrap();
+        let head = fs::read(&log_path).unwrap();
+        DB::open(&dir).unwrap().put("bar", "2").unwrap();
+        let tail = fs::read(&log_path).unwrap()[head.len()..].to_vec();
+        
+        let bad_frames: [&[u8]; 2] = [
+            // An unknown entry type
+            &[CURRENT_VERSION as u8, 1, 0xff],
+            // A size running past the end of the file
+            &[CURRENT_VERSION as u8, 0xff, 0xff, 0x7f],
+        ];
+        for bad_frame in bad_frames {
+            let log = [&head[..], bad_frame, &tail[..]].concat();
+            fs::write(&log_path, &log).unwrap();
+            assert!(DB::open(&dir).is_err());
+            assert!(DB::open_read_only(&dir).is_err());
+            assert_eq!(fs::read(&log_path).unwrap(), log);
+        }
+        
+        // A torn write at the end is cut off
+        let log = [&head[..], &tail[..tail.len() - 1]].concat();
+        fs::write(&log_path, &log).unwrap();
+        {
+            let db = DB::open(&dir).unwrap();
+            assert_eq!(db.get("foo").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(db.get("bar").unwrap(), None);
+        }
+        assert_eq!(fs::read(&log_path).unwrap(), head);
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn in_memory_env() {
+        let dir = test_dir("mem");
+        let env = MemEnv::new();
+        let options = DBOptions {
+            env: Arc::new(env.clone()),
+            ..DBOptions::default()
+        };
+        {
+            let db = DB::open_with_options(&dir, options.clone()).unwrap();
+            db.put("foo", "1").unwrap();
+            db.checkpoint().unwrap();
+            db.put("bar", "2").unwrap();
+        }
+        // Lives as long as the env does, and never on disk
+        assert!(!dir.exists());
+        let db = DB::open_with_options(&dir, options).unwrap();
+        assert_eq!(db.get("foo").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get("bar").unwrap(), Some(b"2".to_vec()));
+        assert!(env.exists(&dir.join(LOG_FILENAME)));
+        assert!(!dir.exists());
+    }
+    
+    #[test]
+    fn async_api_without_ambient_runtime() {
+        let dir = test_dir("async");
+        
+        let db = DB::open(&dir).unwrap();
+        // A runtime that is only used to drive the futures, the DB doesn't
+        // know about it
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            db.put_async("foo", "1").await.unwrap();
+            assert_eq!(db.get_async("foo").await.unwrap(), Some(b"1".to_vec()));
+            db.delete_async("foo").await.unwrap();
+            assert_eq!(db.get_async("foo").await.unwrap(), None);
+        });
+        db.close().unwrap();
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn dropped_async_write_does_not_block_checkpoints() {
+        use std::future::Future;
+        use std::pin::pin;
+        use std::sync::mpsc;
+        use std::task::{Context, Waker};
+        use std::time::Duration;
+        
+        let dir = test_dir("dropped-async");
+        
+        let db = DB::open(&dir).unwrap();
+        {
+            // Poll once so the write begins, then give up on it while it
+            // waits for the log
+            let mut put = pin!(db.put_async("foo", "1"));
+            let mut cx = Context::from_waker(Waker::noop());
+            let _ = put.as_mut().poll(&mut cx);
+        }
+        let (tx, rx) = mpsc::channel();
+        let checkpoint_db = db.clone();
+        thread::spawn(move || tx.send(checkpoint_db.checkpoint()).unwrap());
+        rx.recv_timeout(Duration::from_secs(10)).unwrap().unwrap();
+        db.close().unwrap();
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn write_batch() {
+        let dir = test_dir("batch");
+        
+        {
+            let db = DB::open(&dir).unwrap();
+            db.put("a", "0").unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put("a", "1").put("b", "1").delete("a").put("c", "1");
+            db.write(&batch).unwrap();
+            assert_eq!(db.get("a").unwrap(), None);
+            assert_eq!(db.get("b").unwrap(), Some(b"1".to_vec()));
+        }
+        {
+            let db = DB::open(&dir).unwrap();
+            assert_eq!(db.get("a").unwrap(), None);
+            assert_eq!(db.get("b").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(db.get("c").unwrap(), Some(b"1".to_vec()));
+        }
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn compare_and_swap() {
+        let options = DBOptions {
+            env: Arc::new(MemEnv::new()),
+            ..DBOptions::default()
+        };
+        let db = DB::open_with_options(test_dir("cas"), options).unwrap();
+        assert!(!db.compare_and_swap("a", Some(b"0"), Some(b"1")).unwrap());
+        assert!(db.compare_and_swap("a", None, Some(b"1")).unwrap());
+        assert!(!db.compare_and_swap("a", None, Some(b"2")).unwrap());
+        assert!(db.compare_and_swap("a", Some(b"1"), Some(b"2")).unwrap());
+        assert_eq!(db.get("a").unwrap(), Some(b"2".to_vec()));
+        assert!(db.compare_and_swap("a", Some(b"2"), None).unwrap());
+        assert_eq!(db.get("a").unwrap(), None);
+    }
+    
+    #[test]
+    fn redo_only_log() {
+        use crate::log::io::LogReader;
+        
+        let dir = test_dir("redo");
+        let options = DBOptions {
+            log_format: LogFormat::RedoOnly,
+            ..DBOptions::default()
+        };
+        
+        {
+            let db = DB::open_with_options(&dir, options.clone()).unwrap();
+            db.put("foo", "1").unwrap();
+            db.put("foo", "2").unwrap();
+            db.delete("bar").unwrap();
+        }
+        {
+            let mut reader = LogReader::open(&dir.join(LOG_FILENAME)).unwrap();
+            while let Some(log) = reader.read() {
+                assert!(!matches!(log, LogEntry::Update { .. }));
+            }
+        }
+        {
+            let db = DB::open_with_options(&dir, options).unwrap();
+            assert_eq!(db.get("foo").unwrap(), Some(b"2".to_vec()));
+        }
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn checkpoint_captures_point_of_consistency() {
+        use crate::checkpoint::io::CheckpointReader;
+        
+        let dir = test_dir("consistency");
+        
+        {
+            let db = DB::open(&dir).unwrap();
+            db.put("a", "1").unwrap();
+            db.put("b", "1").unwrap();
+            db.set_phase(CheckpointPhase::PREPARE).unwrap();
+            db.set_phase(CheckpointPhase::RESOLVE).unwrap();
+            // Commits after the point of consistency
+            db.put("a", "2").unwrap();
+            db.delete("b").unwrap();
+            db.put("c", "2").unwrap();
+            db.set_phase(CheckpointPhase::CAPTURE).unwrap();
+            db.save_checkpoint().unwrap();
+            db.set_phase(CheckpointPhase::COMPLETE).unwrap();
+            db.post_checkpoint();
+            db.set_phase(CheckpointPhase::REST).unwrap();
             
-            // TODO: Clean stable_status and stable_record
+            let mut reader = CheckpointReader::open(
+                &dir.join(CHECKPOINT_FILENAME)
+            ).unwrap();
+            let mut content = vec![];
+            while let Some(kv) = reader.read().unwrap() {
+                content.push(kv);
+            }
+            content.sort();
+            assert_eq!(content, vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"1".to_vec()),
+            ]);
+        }
+        {
+            let db = DB::open(&dir).unwrap();
+            assert_eq!(db.get("a").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(db.get("b").unwrap(), None);
+            assert_eq!(db.get("c").unwrap(), Some(b"2".to_vec()));
         }
-        writer.flush();
+        
+        fs::remove_dir_all(&dir).unwrap();
     }
     
-    pub fn post_checkpoint(&self) {
-        // TODO: This should clean up stable_status bit
-        //self.stable_keys = Set::new();
+    #[test]
+    fn ingest() {
+        let dir = test_dir("ingest");
+        
+        {
+            let db = DB::open(&dir).unwrap();
+            db.put("a", "0").unwrap();
+            let log_len = fs::metadata(dir.join(LOG_FILENAME)).unwrap().len();
+            let records = (0..1000).map(|i| {
+                Ok((format!("key{}", i), format!("value{}", i)))
+            });
+            assert_eq!(db.ingest(records).unwrap(), 1000);
+            assert_eq!(db.get("key7").unwrap(), Some(b"value7".to_vec()));
+            // Only the checkpoint phase changes were logged
+            let log_len_after =
+                fs::metadata(dir.join(LOG_FILENAME)).unwrap().len();
+            assert!(log_len_after - log_len < 100);
+            
+            let records = vec![
+                Ok(("b", "1")),
+                Err(Error::new("bad record")),
+                Ok(("c", "1")),
+            ];
+            assert!(db.ingest(records).is_err());
+            assert_eq!(db.get("b").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(db.get("c").unwrap(), None);
+        }
+        {
+            let db = DB::open(&dir).unwrap();
+            assert_eq!(db.get("a").unwrap(), Some(b"0".to_vec()));
+            assert_eq!(db.get("key999").unwrap(), Some(b"value999".to_vec()));
+        }
+        
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
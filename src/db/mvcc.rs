@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use crate::storage::KeyValueStorage;
+use crate::transaction::table::TransactionTable;
+use crate::types::Xid;
+use crate::util::sync::RwLock;
+
+/// The older versions of records that `Concurrency::Mvcc` transactions may
+/// still read.
+///
+/// The latest committed version of every record is its live version, the
+/// one CALC checkpoints. A commit is tagged with an xid taken while it holds
+/// the write lock, and appends the versions it writes to the chains of their
+/// records before changing live storage. A record gets a chain on its first
+/// change, starting with the version it had until then, tagged 0.
+///
+/// A transaction sees the latest version tagged below its own xid. A record
+/// without a chain hasn't changed since the oldest active transaction began,
+/// its live version is the one everybody sees.
+pub(crate) struct VersionChains {
+    chains: RwLock<HashMap<Vec<u8>, Vec<Version>>>,
+}
+
+struct Version {
+    // The xid the commit that wrote it was tagged with
+    xid: Xid,
+    // None for a deleted record
+    value: Option<Vec<u8>>,
+}
+
+/// Versions tagged below the horizon are only read if they are the latest
+/// one below it: every active transaction began after them.
+pub(crate) fn horizon(xtable: &TransactionTable) -> Xid {
+    xtable.oldest_xid().unwrap_or_else(|| xtable.next_xid())
+}
+
+impl VersionChains {
+    pub fn new() -> Self {
+        Self {
+            chains: RwLock::new(HashMap::new()),
+        }
+    }
+    
+    /// Add the versions written by the commit tagged xid, before they are
+    /// applied to live storage
+    pub fn record(
+        &self,
+        live: &dyn KeyValueStorage,
+        xid: Xid,
+        writes: &[(&[u8], Option<&[u8]>)],
+        horizon: Xid,
+    ) {
+        let mut chains = self.chains.write().unwrap();
+        for (key, value) in writes {
+            let chain = chains.entry(key.to_vec()).or_insert_with(|| {
+                vec![Version { xid: 0, value: live.get(key) }]
+            });
+            chain.push(Version { xid, value: value.map(|v| v.to_vec()) });
+            prune(chain, horizon);
+        }
+    }
+    
+    /// The version of key a transaction with the given xid sees
+    pub fn get(&self, live: &dyn KeyValueStorage, key: &[u8], xid: Xid)
+        -> Option<Vec<u8>>
+    {
+        // Read the live version before looking for a chain: a commit records
+        // its versions before changing the live one, so if the live version
+        // we read is too new the chain is there.
+        let live_value = live.get(key);
+        let chains = self.chains.read().unwrap();
+        let chain = match chains.get(key) {
+            Some(chain) => chain,
+            None => return live_value,
+        };
+        match chain.iter().rev().find(|version| version.xid < xid) {
+            Some(version) => version.value.clone(),
+            None => live_value,
+        }
+    }
+    
+    /// Whether a commit tagged after xid wrote key
+    pub fn changed_after(&self, key: &[u8], xid: Xid) -> bool {
+        let chains = self.chains.read().unwrap();
+        chains.get(key)
+            .and_then(|chain| chain.last())
+            .is_some_and(|version| version.xid > xid)
+    }
+    
+    /// Whether a commit tagged after xid wrote a key starting with prefix
+    pub fn prefix_changed_after(&self, prefix: &[u8], xid: Xid) -> bool {
+        let chains = self.chains.read().unwrap();
+        chains.iter().any(|(key, chain)| {
+            key.starts_with(prefix)
+                && chain.last().is_some_and(|version| version.xid > xid)
+        })
+    }
+    
+    /// Keys with a chain, in no particular order
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.chains.read().unwrap().keys().cloned().collect()
+    }
+    
+    /// Drop the versions no active transaction can read any more, and the
+    /// chains left with only the live version
+    pub fn collect(&self, horizon: Xid) {
+        let mut chains = self.chains.write().unwrap();
+        chains.retain(|_, chain| {
+            prune(chain, horizon);
+            chain.len() > 1
+        });
+    }
+    
+    /// Number of versions kept in chains
+    pub fn len(&self) -> usize {
+        let chains = self.chains.read().unwrap();
+        chains.values().map(Vec::len).sum()
+    }
+}
+
+// Drop the versions replaced by a later one below horizon
+fn prune(chain: &mut Vec<Version>, horizon: Xid) {
+    if let Some(visible) = chain.iter().rposition(|v| v.xid < horizon) {
+        chain.drain(..visible);
+    }
+}
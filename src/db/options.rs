@@ -0,0 +1,88 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::env::{Env, RealEnv};
+
+/// How updates are written to the WAL
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Every update carries the value it replaced as well as the new one
+    /// (LogEntry::Update). Useful when inspecting the log, see thorkv-tool.
+    Physical,
+    /// Updates only carry the new value (LogEntry::Operation), which halves
+    /// the log volume of overwrites.
+    ///
+    /// Recovery is redo-only, it starts from a transactionally consistent
+    /// CALC checkpoint and replays committed transactions, so before-images
+    /// are never read back. Uncommitted writes live in the transaction until
+    /// it commits and never reach live storage or the log, rolling back just
+    /// drops them.
+    RedoOnly,
+}
+
+/// How transactions running at the same time are kept apart
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Concurrency {
+    /// Only the latest version of each record is kept and commits are
+    /// serialized by a lock, there are no multi-statement transactions.
+    /// The cheapest in memory.
+    Locking,
+    /// Multi-version concurrency control: `DB::begin` starts a transaction
+    /// that sees the database as of when it began (snapshot isolation, see
+    /// `IsolationLevel` for the others), and its reads never block or are
+    /// blocked by writers.
+    ///
+    /// A record changed by a commit keeps its older versions, tagged with
+    /// the xid ordering that commit, for as long as an active transaction
+    /// may read them; they are garbage-collected once `oldest_xid` of the
+    /// transaction table has moved past them. A transaction that stays open
+    /// holds back that collection until it runs past its timeout (see
+    /// `transaction_timeout`). Checkpoints only wait for the transactions
+    /// that are committing.
+    ///
+    /// Writes are buffered in the transaction until it commits. Of two
+    /// snapshot-isolated transactions writing the same key the first to
+    /// commit wins, the other one fails with `ErrorKind::Conflict`.
+    Mvcc,
+}
+
+/// Settings for `DB::open_with_options`
+#[derive(Clone)]
+pub struct DBOptions {
+    pub log_format: LogFormat,
+    pub concurrency: Concurrency,
+    /// How long a transaction begun with `DB::begin` may run before it's
+    /// aborted, unless it sets its own with `Transaction::set_timeout`.
+    /// None by default, for no limit.
+    ///
+    /// Checkpoints, `DB::backup_to` and `DB::ingest` don't wait for open
+    /// transactions, only for the ones committing. But until it ends, an
+    /// open transaction keeps every version of a record it may read in
+    /// memory, so with no limit a forgotten one makes them pile up.
+    pub transaction_timeout: Option<Duration>,
+    /// Where the database directory lives, the local filesystem by default.
+    /// With a `MemEnv` the database is a pure in-memory cache.
+    pub env: Arc<dyn Env>,
+}
+
+impl Default for DBOptions {
+    fn default() -> Self {
+        Self {
+            log_format: LogFormat::Physical,
+            concurrency: Concurrency::Locking,
+            transaction_timeout: None,
+            env: Arc::new(RealEnv),
+        }
+    }
+}
+
+impl fmt::Debug for DBOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DBOptions")
+            .field("log_format", &self.log_format)
+            .field("concurrency", &self.concurrency)
+            .field("transaction_timeout", &self.transaction_timeout)
+            .finish()
+    }
+}
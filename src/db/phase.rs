@@ -0,0 +1,95 @@
+use crate::transaction::table::TransactionTable;
+use crate::types::{CheckpointPhase, Error, Xid};
+use crate::util::sync::{RwLock, RwLockReadGuard};
+
+/// The checkpoint phase the database is in.
+///
+/// Commits hold the phase shared while they are logged and applied, and a
+/// switch takes it exclusively, so each commit happens entirely before or
+/// after a phase change, both in memory and in the log.
+pub(crate) struct PhaseLock {
+    phase: RwLock<CheckpointPhase>,
+}
+
+impl PhaseLock {
+    pub fn new() -> Self {
+        Self {
+            phase: RwLock::new(CheckpointPhase::REST),
+        }
+    }
+    
+    /// Keep the current phase until the guard is dropped
+    pub fn read(&self) -> RwLockReadGuard<'_, CheckpointPhase> {
+        self.phase.read().unwrap()
+    }
+    
+    /// Switch to phase, calling before with the phase being left once no
+    /// commit is in progress. The phase doesn't change if before fails.
+    ///
+    /// Returns the next xid of xtable as of the switch. Every transaction
+    /// that committed in an earlier phase has a smaller xid, so they have
+    /// all ended once oldest_xid has reached it.
+    pub fn switch<F>(
+        &self,
+        phase: CheckpointPhase,
+        xtable: &TransactionTable,
+        before: F,
+    ) -> Result<Xid, Error>
+    where
+        F: FnOnce(CheckpointPhase) -> Result<(), Error>
+    {
+        let mut phase_guard = self.phase.write().unwrap();
+        before(*phase_guard)?;
+        *phase_guard = phase;
+        Ok(xtable.next_xid())
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use crate::util::sync::Mutex;
+    use loom::sync::Arc;
+    use loom::thread;
+    
+    // The checkpointer switches phase, then waits for oldest_xid to reach
+    // the xid the switch returned. By then a commit applied in the phase
+    // that was left must have ended.
+    #[test]
+    fn loom_switch_then_wait_covers_earlier_commits() {
+        loom::model(|| {
+            let xtable = Arc::new(TransactionTable::new());
+            let phase = Arc::new(PhaseLock::new());
+            let committed = Arc::new(Mutex::new(None));
+            let committer = {
+                let (xtable, phase) = (xtable.clone(), phase.clone());
+                let committed = committed.clone();
+                thread::spawn(move || {
+                    let xid = xtable.begin();
+                    let phase = phase.read();
+                    *committed.lock().unwrap() = Some((xid, *phase));
+                    drop(phase);
+                    xtable.end(&xid);
+                })
+            };
+            
+            let next_xid = phase
+                .switch(CheckpointPhase::PREPARE, &xtable, |_| Ok(()))
+                .unwrap();
+            while let Some(oldest) = xtable.oldest_xid() {
+                if oldest >= next_xid {
+                    break;
+                }
+                thread::yield_now();
+            }
+            if let Some((xid, CheckpointPhase::REST)) =
+                *committed.lock().unwrap()
+            {
+                assert!(xid < next_xid);
+                assert_ne!(xtable.oldest_xid(), Some(xid));
+            }
+            
+            committer.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::Arc;
+
+use crate::db::versions::StableVersions;
+use crate::db::DB;
+use crate::types::Error;
+
+/// A consistent, read-only view of the database as of `DB::snapshot`.
+///
+/// Writers are not blocked while a snapshot is open. Instead a record is
+/// copied the first time it changes after the snapshot was taken, the same
+/// way a checkpoint keeps the stable version of a record, so an open
+/// snapshot costs memory in proportion to what is written meanwhile. The
+/// copies are dropped along with the snapshot.
+///
+/// A snapshot never sees part of a `WriteBatch`.
+pub struct Snapshot<'a> {
+    db: &'a DB,
+    versions: Arc<StableVersions>,
+}
+
+impl DB {
+    /// Take a snapshot of the database as it is now
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        // Every commit is entirely before or after the snapshot
+        let _write_guard = self.write_lock.lock().unwrap();
+        let versions = self.versions.open_snapshot();
+        Snapshot { db: self, versions }
+    }
+}
+
+impl<'a> Snapshot<'a> {
+    pub fn get<K>(&self, key: K) -> Result<Option<Vec<u8>>, Error>
+    where K: AsRef<[u8]>
+    {
+        Ok(self.db.versions.get_in(&self.versions, key.as_ref()))
+    }
+    
+    /// Iterate over every key-value pair in the snapshot, in no particular
+    /// order
+    pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        let versions = &self.db.versions;
+        versions.keys_in(&self.versions)
+            .into_iter()
+            .filter_map(move |key| {
+                let value = versions.get_in(&self.versions, &key)?;
+                Some((key, value))
+            })
+    }
+}
+
+impl<'a> Drop for Snapshot<'a> {
+    fn drop(&mut self) {
+        self.db.versions.close_snapshot(&self.versions);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::thread;
+    
+    use crate::db::{DBOptions, DBRef, WriteBatch};
+    use crate::env::MemEnv;
+    
+    fn open() -> DBRef {
+        let options = DBOptions {
+            env: Arc::new(MemEnv::new()),
+            ..DBOptions::default()
+        };
+        DB::open_with_options("/thorkv", options).unwrap()
+    }
+    
+    fn contents(snapshot: &Snapshot) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        snapshot.iter().collect()
+    }
+    
+    #[test]
+    fn snapshot_keeps_state_as_of_creation() {
+        let db = open();
+        db.put("a", "1").unwrap();
+        db.put("b", "1").unwrap();
+        let snapshot = db.snapshot();
+        db.put("a", "2").unwrap();
+        db.delete("b").unwrap();
+        db.put("c", "1").unwrap();
+        db.checkpoint().unwrap();
+        let later = db.snapshot();
+        
+        assert_eq!(snapshot.get("a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snapshot.get("b").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snapshot.get("c").unwrap(), None);
+        let expected = vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"1".to_vec()),
+        ];
+        assert_eq!(contents(&snapshot), expected.into_iter().collect());
+        
+        db.put("a", "3").unwrap();
+        assert_eq!(later.get("a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(later.get("b").unwrap(), None);
+        assert_eq!(contents(&later).len(), 2);
+        assert_eq!(db.get("a").unwrap(), Some(b"3".to_vec()));
+    }
+    
+    #[test]
+    fn snapshot_ignores_ingested_data() {
+        let db = open();
+        db.put("a", "1").unwrap();
+        let snapshot = db.snapshot();
+        let records = vec![Ok(("a", "2")), Ok(("b", "2"))];
+        db.ingest(records).unwrap();
+        
+        assert_eq!(snapshot.get("a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snapshot.get("b").unwrap(), None);
+        assert_eq!(contents(&snapshot).len(), 1);
+        assert_eq!(db.get("b").unwrap(), Some(b"2".to_vec()));
+    }
+    
+    #[test]
+    fn snapshots_never_see_part_of_a_batch() {
+        let db = open();
+        let stop = AtomicBool::new(false);
+        let batches = AtomicU64::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut i = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    let value = i.to_string();
+                    let mut batch = WriteBatch::new();
+                    batch.put("a", &value).put("b", &value);
+                    if i % 2 == 0 {
+                        batch.delete("c");
+                    } else {
+                        batch.put("c", &value);
+                    }
+                    db.write(&batch).unwrap();
+                    i += 1;
+                    batches.store(i, Ordering::SeqCst);
+                }
+            });
+            s.spawn(|| {
+                while !stop.load(Ordering::SeqCst) {
+                    db.checkpoint().unwrap();
+                }
+            });
+            // Keep taking snapshots while a good number of batches goes by
+            while batches.load(Ordering::SeqCst) < 2000 {
+                let snapshot = db.snapshot();
+                let pairs = contents(&snapshot);
+                assert_eq!(pairs.get(&b"a"[..]), pairs.get(&b"b"[..]));
+                if let Some(c) = pairs.get(&b"c"[..]) {
+                    assert_eq!(Some(c), pairs.get(&b"a"[..]));
+                }
+                assert_eq!(
+                    snapshot.get("a").unwrap().as_ref(),
+                    pairs.get(&b"a"[..])
+                );
+            }
+            stop.store(true, Ordering::SeqCst);
+        });
+    }
+}
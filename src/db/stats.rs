@@ -0,0 +1,211 @@
+use std::fmt::Write;
+
+use crate::db::DB;
+use crate::metrics::{self, HistogramSnapshot, CHECKPOINT_PHASES};
+use crate::types::CheckpointPhase;
+
+/// Point-in-time copy of what a DB records about itself, see `DB::stats`.
+///
+/// Latencies and durations are in microseconds.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub get_latency: HistogramSnapshot,
+    pub put_latency: HistogramSnapshot,
+    pub delete_latency: HistogramSnapshot,
+    pub write_latency: HistogramSnapshot,
+    /// Bytes written to the WAL since open
+    pub wal_bytes: u64,
+    pub fsyncs: u64,
+    pub fsync_latency: HistogramSnapshot,
+    /// Log entries written per fsync
+    pub flush_entries: HistogramSnapshot,
+    /// Checkpoints completed since open
+    pub checkpoints: u64,
+    /// Time spent in each checkpoint phase but REST
+    pub checkpoint_phase_duration: Vec<(CheckpointPhase, HistogramSnapshot)>,
+    /// Number of keys with a stable version kept for the running checkpoint
+    pub stable_keys: u64,
+    pub active_transactions: u64,
+    /// Record versions kept for MVCC transactions, see `Concurrency::Mvcc`
+    pub mvcc_versions: u64,
+    /// Resident memory of the process, None where it can't be measured
+    pub resident_memory_bytes: Option<u64>,
+}
+
+impl DB {
+    /// Snapshot of the metrics recorded since the database was opened.
+    pub fn stats(&self) -> Stats {
+        let metrics = &self.metrics;
+        let checkpoint_phase_duration = CHECKPOINT_PHASES.iter()
+            .map(|phase| {
+                let histogram = metrics.phase_duration(*phase).unwrap();
+                (*phase, histogram.snapshot())
+            })
+            .collect();
+        Stats {
+            get_latency: metrics.get_latency.snapshot(),
+            put_latency: metrics.put_latency.snapshot(),
+            delete_latency: metrics.delete_latency.snapshot(),
+            write_latency: metrics.write_latency.snapshot(),
+            wal_bytes: metrics.wal_bytes.get(),
+            fsyncs: metrics.fsyncs.get(),
+            fsync_latency: metrics.fsync_latency.snapshot(),
+            flush_entries: metrics.flush_entries.snapshot(),
+            checkpoints: metrics.checkpoints.get(),
+            checkpoint_phase_duration,
+            stable_keys: self.versions.stable_len() as u64,
+            active_transactions: self.xtable.active_count() as u64,
+            mvcc_versions: self.mvcc.as_ref().map_or(0, |c| c.len() as u64),
+            resident_memory_bytes: metrics::resident_memory_bytes(),
+        }
+    }
+}
+
+// Microseconds to the seconds Prometheus expects
+const MICROS: f64 = 1_000_000.0;
+
+impl Stats {
+    /// Render in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        
+        header(&mut out, "thorkv_operation_duration_seconds", "histogram",
+               "Latency of DB operations");
+        let operations = [
+            ("get", &self.get_latency),
+            ("put", &self.put_latency),
+            ("delete", &self.delete_latency),
+            ("write", &self.write_latency),
+        ];
+        for (op, histogram) in &operations {
+            let labels = format!("op=\"{}\"", op);
+            histogram_lines(&mut out, "thorkv_operation_duration_seconds",
+                            &labels, histogram, MICROS);
+        }
+        
+        header(&mut out, "thorkv_wal_written_bytes_total", "counter",
+               "Bytes written to the WAL");
+        sample(&mut out, "thorkv_wal_written_bytes_total", self.wal_bytes);
+        header(&mut out, "thorkv_wal_fsyncs_total", "counter",
+               "Number of WAL fsyncs");
+        sample(&mut out, "thorkv_wal_fsyncs_total", self.fsyncs);
+        header(&mut out, "thorkv_wal_fsync_duration_seconds", "histogram",
+               "Latency of WAL fsyncs");
+        histogram_lines(&mut out, "thorkv_wal_fsync_duration_seconds", "",
+                        &self.fsync_latency, MICROS);
+        header(&mut out, "thorkv_wal_flush_entries", "histogram",
+               "Log entries written per fsync (group commit batch size)");
+        histogram_lines(&mut out, "thorkv_wal_flush_entries", "",
+                        &self.flush_entries, 1.0);
+        
+        header(&mut out, "thorkv_checkpoints_total", "counter",
+               "Number of checkpoints completed");
+        sample(&mut out, "thorkv_checkpoints_total", self.checkpoints);
+        header(&mut out, "thorkv_checkpoint_phase_duration_seconds",
+               "histogram", "Time spent in each checkpoint phase");
+        for (phase, histogram) in &self.checkpoint_phase_duration {
+            let labels = format!("phase=\"{:?}\"", phase);
+            histogram_lines(&mut out,
+                            "thorkv_checkpoint_phase_duration_seconds",
+                            &labels, histogram, MICROS);
+        }
+        
+        header(&mut out, "thorkv_stable_keys", "gauge",
+               "Keys with a stable version held for the running checkpoint");
+        sample(&mut out, "thorkv_stable_keys", self.stable_keys);
+        header(&mut out, "thorkv_active_transactions", "gauge",
+               "Transactions in progress");
+        sample(&mut out, "thorkv_active_transactions",
+               self.active_transactions);
+        header(&mut out, "thorkv_mvcc_versions", "gauge",
+               "Record versions kept for MVCC transactions");
+        sample(&mut out, "thorkv_mvcc_versions", self.mvcc_versions);
+        if let Some(bytes) = self.resident_memory_bytes {
+            header(&mut out, "thorkv_resident_memory_bytes", "gauge",
+                   "Resident memory of the process");
+            sample(&mut out, "thorkv_resident_memory_bytes", bytes);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, value: u64) {
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// Bucket, sum and count lines of a histogram, values are divided by scale
+fn histogram_lines(
+    out: &mut String,
+    name: &str,
+    labels: &str,
+    histogram: &HistogramSnapshot,
+    scale: f64,
+) {
+    let sep = if labels.is_empty() { "" } else { "," };
+    for (bound, count) in &histogram.buckets {
+        let le = *bound as f64 / scale;
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}",
+                         name, labels, sep, le, count);
+    }
+    let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+                     name, labels, sep, histogram.count);
+    let labels = if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    };
+    let sum = histogram.sum as f64 / scale;
+    let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+    let _ = writeln!(out, "{}_count{} {}", name, labels, histogram.count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn stats_record_operations() {
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-db-stats-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        
+        {
+            let db = DB::open(&dir).unwrap();
+            db.put("a", "1").unwrap();
+            db.put("b", "2").unwrap();
+            db.delete("a").unwrap();
+            assert_eq!(db.get("b").unwrap(), Some(b"2".to_vec()));
+            db.checkpoint().unwrap();
+            
+            let stats = db.stats();
+            assert_eq!(stats.put_latency.count, 2);
+            assert_eq!(stats.delete_latency.count, 1);
+            assert_eq!(stats.get_latency.count, 1);
+            assert!(stats.wal_bytes > 0);
+            assert!(stats.fsyncs > 0);
+            assert_eq!(stats.checkpoints, 1);
+            for (_, histogram) in &stats.checkpoint_phase_duration {
+                assert_eq!(histogram.count, 1);
+            }
+            assert_eq!(stats.active_transactions, 0);
+            
+            let text = stats.to_prometheus();
+            assert!(text.contains(
+                "thorkv_operation_duration_seconds_count{op=\"put\"} 2\n"
+            ));
+            assert!(text.contains(
+                "thorkv_checkpoint_phase_duration_seconds_bucket\
+                 {phase=\"CAPTURE\",le=\"+Inf\"} 1\n"
+            ));
+            assert!(text.contains("thorkv_checkpoints_total 1\n"));
+            assert!(text.contains("# TYPE thorkv_wal_fsyncs_total counter\n"));
+        }
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,703 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::db::{TransactionInfo, TransactionState, WriteBatch, DB};
+use crate::types::{Error, ErrorKind, Timestamp, Xid};
+
+/// How much a transaction sees of the transactions running alongside it.
+///
+/// Every level reads the transaction's own writes, and never reads writes
+/// that aren't committed. The levels differ in what they allow of the
+/// anomalies below:
+///
+/// - A phantom: reading the keys matching a prefix twice and getting a key
+///   the first scan didn't, committed by another transaction in between.
+/// - A lost update: two transactions read a key and both write it back
+///   based on what they read, the first write is lost.
+/// - Write skew: two transactions read the same keys and each writes a
+///   different one, so each commits based on a state that no longer holds.
+///
+/// | Level          | Phantoms | Lost updates | Write skew |
+/// |----------------|----------|--------------|------------|
+/// | ReadCommitted  | yes      | yes          | yes        |
+/// | RepeatableRead | no       | no           | yes        |
+/// | Serializable   | no       | no           | no         |
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IsolationLevel {
+    /// Every read sees the latest committed version as of that read, and
+    /// writes never conflict, the last one to commit wins.
+    ReadCommitted,
+    /// Snapshot isolation: reads see the database as of when the
+    /// transaction began, and of two transactions writing the same key
+    /// the first to commit wins.
+    RepeatableRead,
+    /// Like RepeatableRead, but the commit also fails if a concurrent
+    /// transaction committed a write to a key this one read, or to a key
+    /// matching a prefix it scanned. Serializable transactions behave as if
+    /// they ran one at a time in the order they commit.
+    Serializable,
+}
+
+/// A transaction over a database opened with `Concurrency::Mvcc`.
+///
+/// Writes are buffered until `commit`, which applies all of them atomically,
+/// or fails with `ErrorKind::Conflict` if a concurrent transaction got in
+/// the way, see `IsolationLevel`. Dropping the transaction without
+/// committing rolls it back.
+///
+/// A transaction that runs past its timeout is aborted, see
+/// `DBOptions::transaction_timeout`.
+///
+/// Savepoints mark a point to roll back to without giving up on the whole
+/// transaction. As writes don't reach the log or live storage before the
+/// commit, rolling back to a savepoint only drops the writes buffered
+/// since, and the commit logs just the writes that are left.
+pub struct Transaction<'a> {
+    db: &'a DB,
+    xid: Xid,
+    isolation: IsolationLevel,
+    writes: WriteBatch,
+    // What a serializable transaction read, validated on commit
+    reads: RefCell<ReadSet>,
+    // Name and number of writes buffered, oldest first
+    savepoints: Vec<(String, usize)>,
+}
+
+#[derive(Default)]
+struct ReadSet {
+    keys: HashSet<Vec<u8>>,
+    prefixes: Vec<Vec<u8>>,
+}
+
+impl DB {
+    /// Begin a transaction with RepeatableRead isolation.
+    ///
+    /// Fails unless the database was opened with `Concurrency::Mvcc`.
+    pub fn begin(&self) -> Result<Transaction<'_>, Error> {
+        self.begin_with_isolation(IsolationLevel::RepeatableRead)
+    }
+    
+    pub fn begin_with_isolation(&self, isolation: IsolationLevel)
+        -> Result<Transaction<'_>, Error>
+    {
+        self.check_writable()?;
+        if self.mvcc.is_none() {
+            return Err(Error::new("Transactions need Concurrency::Mvcc"));
+        }
+        // Commits tagged below the xid are entirely applied by now
+        let xid = {
+            let _write_guard = self.write_lock.lock().unwrap();
+            self.xtable.begin_tracked(self.options.transaction_timeout)
+        };
+        Ok(Transaction {
+            db: self,
+            xid,
+            isolation,
+            writes: WriteBatch::new(),
+            reads: RefCell::new(ReadSet::default()),
+            savepoints: Vec::new(),
+        })
+    }
+}
+
+impl<'a> Transaction<'a> {
+    pub fn xid(&self) -> Xid {
+        self.xid
+    }
+    
+    pub fn isolation(&self) -> IsolationLevel {
+        self.isolation
+    }
+    
+    /// When the transaction began
+    pub fn started(&self) -> Timestamp {
+        self.info().started
+    }
+    
+    pub fn state(&self) -> TransactionState {
+        self.info().state
+    }
+    
+    fn info(&self) -> TransactionInfo {
+        // Tracked until dropped
+        self.db.xtable.info(self.xid).unwrap()
+    }
+    
+    /// Abort the transaction once it has run for timeout since it began,
+    /// None to let it run for as long as it likes
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.db.xtable.set_timeout(self.xid, timeout);
+    }
+    
+    pub fn get<K>(&self, key: K) -> Result<Option<Vec<u8>>, Error>
+    where K: AsRef<[u8]>
+    {
+        let key = key.as_ref();
+        let value = match self.writes.get(key) {
+            Some(value) => value.map(|v| v.to_vec()),
+            None => self.read(key),
+        };
+        self.check_active()?;
+        Ok(value)
+    }
+    
+    fn read(&self, key: &[u8]) -> Option<Vec<u8>> {
+        if self.isolation == IsolationLevel::Serializable {
+            self.reads.borrow_mut().keys.insert(key.to_vec());
+        }
+        if self.isolation == IsolationLevel::ReadCommitted {
+            return self.db.versions.get(key);
+        }
+        let live = self.db.versions.live();
+        let chains = self.db.mvcc.as_ref().unwrap();
+        chains.get(&**live, key, self.xid)
+    }
+    
+    // Checked after reading: once aborted, the versions a transaction reads
+    // may be collected. If it's still active they weren't while it read.
+    fn check_active(&self) -> Result<(), Error> {
+        if self.state() == TransactionState::Aborted {
+            return Err(self.aborted());
+        }
+        Ok(())
+    }
+    
+    fn aborted(&self) -> Error {
+        Error::with_kind(
+            ErrorKind::Aborted,
+            format!("Transaction {} ran past its timeout", self.xid),
+        )
+    }
+    
+    /// Every key starting with prefix along with its value
+    pub fn scan<P>(&self, prefix: P)
+        -> Result<BTreeMap<Vec<u8>, Vec<u8>>, Error>
+    where P: AsRef<[u8]>
+    {
+        let prefix = prefix.as_ref();
+        if self.isolation == IsolationLevel::Serializable {
+            self.reads.borrow_mut().prefixes.push(prefix.to_vec());
+        }
+        let live = &**self.db.versions.live();
+        let mut pairs = BTreeMap::new();
+        if self.isolation == IsolationLevel::ReadCommitted {
+            // A snapshot so that the scan sees a commit whole or not at all
+            let snapshot = self.db.snapshot();
+            pairs.extend(
+                snapshot.iter().filter(|(key, _)| key.starts_with(prefix))
+            );
+        } else {
+            // Live keys first, a key deleted after this is in the list, one
+            // deleted before has a chain by now
+            let chains = self.db.mvcc.as_ref().unwrap();
+            let mut keys = live.keys();
+            keys.extend(chains.keys());
+            for key in keys {
+                if key.starts_with(prefix) {
+                    if let Some(value) = chains.get(live, &key, self.xid) {
+                        pairs.insert(key, value);
+                    }
+                }
+            }
+        }
+        self.check_active()?;
+        for (key, value) in self.writes.writes() {
+            if !key.starts_with(prefix) {
+                continue;
+            }
+            match value {
+                Some(value) => pairs.insert(key.to_vec(), value.to_vec()),
+                None => pairs.remove(key),
+            };
+        }
+        Ok(pairs)
+    }
+    
+    pub fn put<K, V>(&mut self, key: K, value: V)
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>
+    {
+        self.writes.put(key, value);
+    }
+    
+    pub fn delete<K>(&mut self, key: K)
+    where
+        K: AsRef<[u8]>
+    {
+        self.writes.delete(key);
+    }
+    
+    /// Mark the current point of the transaction as name. A savepoint with
+    /// the same name as an earlier one hides it until rolled back past.
+    pub fn savepoint<S: Into<String>>(&mut self, name: S) {
+        self.savepoints.push((name.into(), self.writes.len()));
+    }
+    
+    /// Undo the writes made since the latest savepoint named name, and drop
+    /// the savepoints taken after it. The savepoint itself stays.
+    ///
+    /// Nothing is logged and no previous_value is applied: the undone writes
+    /// were only buffered, so the log and live storage never had them.
+    ///
+    /// Reads aren't undone, a serializable transaction still fails to
+    /// commit if what it read since has changed.
+    pub fn rollback_to(&mut self, name: &str) -> Result<(), Error> {
+        let index = self.savepoints.iter()
+            .rposition(|(savepoint, _)| savepoint == name)
+            .ok_or_else(|| Error::new(format!("No savepoint {}", name)))?;
+        self.writes.truncate(self.savepoints[index].1);
+        self.savepoints.truncate(index + 1);
+        Ok(())
+    }
+    
+    /// Apply the writes of the transaction and wait for them to be durable
+    pub fn commit(self) -> Result<(), Error> {
+        let db = self.db;
+        db.check_writable()?;
+        // Reads alone are consistent as of when the transaction began
+        if self.writes.is_empty() {
+            return self.check_active();
+        }
+        // From here on the transaction can't be aborted
+        if !db.xtable.start_commit(self.xid) {
+            return Err(self.aborted());
+        }
+        let start = Instant::now();
+        let writes = self.writes.writes();
+        let res = match db.log_and_apply_if(self.xid, &writes, || {
+            self.validate(&writes)
+        }) {
+            Ok(Some(lsn)) => db.log.as_ref().unwrap().wait_durable(lsn),
+            Ok(None) => Err(Error::with_kind(
+                ErrorKind::Conflict,
+                "A concurrent transaction committed a conflicting write",
+            )),
+            Err(err) => Err(err),
+        };
+        db.metrics.write_latency.observe_duration(start.elapsed());
+        res
+    }
+    
+    /// Drop the writes of the transaction
+    pub fn rollback(self) {}
+    
+    // Whether the transaction can commit, called once no other transaction
+    // can
+    fn validate(&self, writes: &[(&[u8], Option<&[u8]>)]) -> bool {
+        let chains = self.db.mvcc.as_ref().unwrap();
+        let changed = |key: &[u8]| chains.changed_after(key, self.xid);
+        match self.isolation {
+            IsolationLevel::ReadCommitted => true,
+            IsolationLevel::RepeatableRead => {
+                !writes.iter().any(|(key, _)| changed(key))
+            }
+            IsolationLevel::Serializable => {
+                let reads = self.reads.borrow();
+                !writes.iter().any(|(key, _)| changed(key))
+                    && !reads.keys.iter().any(|key| changed(key))
+                    && !reads.prefixes.iter().any(|prefix| {
+                        chains.prefix_changed_after(prefix, self.xid)
+                    })
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        self.db.xtable.forget(self.xid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    
+    use crate::constants::LOG_FILENAME;
+    use crate::db::{Concurrency, DBOptions, DBRef};
+    use crate::env::MemEnv;
+    
+    fn open(env: &MemEnv) -> DBRef {
+        let options = DBOptions {
+            concurrency: Concurrency::Mvcc,
+            env: Arc::new(env.clone()),
+            ..DBOptions::default()
+        };
+        DB::open_with_options("/thorkv", options).unwrap()
+    }
+    
+    #[test]
+    fn transactions_read_as_of_when_they_began() {
+        let db = open(&MemEnv::new());
+        db.put("a", "1").unwrap();
+        db.put("b", "1").unwrap();
+        let txn = db.begin().unwrap();
+        db.put("a", "2").unwrap();
+        db.delete("b").unwrap();
+        db.put("c", "1").unwrap();
+        
+        assert_eq!(txn.get("a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(txn.get("b").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(txn.get("c").unwrap(), None);
+        let later = db.begin().unwrap();
+        assert_eq!(later.get("a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(later.get("b").unwrap(), None);
+        assert_eq!(db.get("a").unwrap(), Some(b"2".to_vec()));
+    }
+    
+    #[test]
+    fn first_committer_wins() {
+        let db = open(&MemEnv::new());
+        let mut first = db.begin().unwrap();
+        let mut second = db.begin().unwrap();
+        first.put("a", "1");
+        second.put("b", "2");
+        second.put("a", "2");
+        first.commit().unwrap();
+        
+        let err = second.commit().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Conflict);
+        assert_eq!(db.get("a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get("b").unwrap(), None);
+        
+        // Writing keys nobody else wrote, or after the other commit, is fine
+        let mut third = db.begin().unwrap();
+        third.put("a", "3");
+        let mut fourth = db.begin().unwrap();
+        fourth.put("b", "4");
+        fourth.commit().unwrap();
+        third.commit().unwrap();
+        assert_eq!(db.get("a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(db.get("b").unwrap(), Some(b"4".to_vec()));
+    }
+    
+    #[test]
+    fn old_versions_are_collected() {
+        let db = open(&MemEnv::new());
+        let chains = db.mvcc.as_ref().unwrap();
+        db.put("a", "1").unwrap();
+        let txn = db.begin().unwrap();
+        for i in 2..10 {
+            db.put("a", i.to_string()).unwrap();
+        }
+        // Everything from the version the transaction reads on is kept
+        assert_eq!(txn.get("a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(chains.len(), 9);
+        txn.rollback();
+        db.put("a", "10").unwrap();
+        assert_eq!(chains.len(), 2);
+        db.checkpoint().unwrap();
+        assert_eq!(chains.len(), 0);
+        assert_eq!(db.stats().mvcc_versions, 0);
+    }
+    
+    const LEVELS: [IsolationLevel; 3] = [
+        IsolationLevel::ReadCommitted,
+        IsolationLevel::RepeatableRead,
+        IsolationLevel::Serializable,
+    ];
+    
+    fn pair(key: &str, value: &str) -> (Vec<u8>, Vec<u8>) {
+        (key.as_bytes().to_vec(), value.as_bytes().to_vec())
+    }
+    
+    #[test]
+    fn transactions_read_their_own_writes() {
+        for isolation in LEVELS {
+            let db = open(&MemEnv::new());
+            db.put("a", "1").unwrap();
+            db.put("b", "1").unwrap();
+            let mut txn = db.begin_with_isolation(isolation).unwrap();
+            txn.put("a", "2");
+            txn.delete("b");
+            txn.put("c", "2");
+            
+            assert_eq!(txn.get("a").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(txn.get("b").unwrap(), None);
+            let expected = vec![pair("a", "2"), pair("c", "2")];
+            assert_eq!(txn.scan("").unwrap(), expected.into_iter().collect());
+            // Nobody else does until it commits
+            assert_eq!(db.get("a").unwrap(), Some(b"1".to_vec()));
+            txn.commit().unwrap();
+            assert_eq!(db.get("c").unwrap(), Some(b"2".to_vec()));
+        }
+    }
+    
+    #[test]
+    fn phantoms() {
+        for isolation in LEVELS {
+            let db = open(&MemEnv::new());
+            db.put("item/1", "1").unwrap();
+            db.put("other", "1").unwrap();
+            let mut txn = db.begin_with_isolation(isolation).unwrap();
+            let items = txn.scan("item/").unwrap();
+            assert_eq!(items.get(&b"item/1"[..]), Some(&b"1".to_vec()));
+            db.put("item/2", "1").unwrap();
+            
+            let items = txn.scan("item/").unwrap();
+            if isolation == IsolationLevel::ReadCommitted {
+                assert_eq!(items.len(), 2, "{:?}", isolation);
+            } else {
+                assert_eq!(items.len(), 1, "{:?}", isolation);
+            }
+            // Acting on the first scan is only safe if serializable
+            txn.put("count", "1");
+            let res = txn.commit();
+            if isolation == IsolationLevel::Serializable {
+                assert_eq!(res.unwrap_err().kind(), ErrorKind::Conflict);
+            } else {
+                res.unwrap();
+            }
+        }
+    }
+    
+    #[test]
+    fn lost_updates() {
+        for isolation in LEVELS {
+            let db = open(&MemEnv::new());
+            db.put("counter", "0").unwrap();
+            let mut first = db.begin_with_isolation(isolation).unwrap();
+            let mut second = db.begin_with_isolation(isolation).unwrap();
+            for txn in [&mut first, &mut second] {
+                let value = txn.get("counter").unwrap().unwrap();
+                let n: u64 = String::from_utf8(value).unwrap().parse().unwrap();
+                txn.put("counter", (n + 1).to_string());
+            }
+            first.commit().unwrap();
+            
+            let res = second.commit();
+            if isolation == IsolationLevel::ReadCommitted {
+                // Both increments commit, one of them is lost
+                res.unwrap();
+            } else {
+                assert_eq!(res.unwrap_err().kind(), ErrorKind::Conflict);
+            }
+            assert_eq!(db.get("counter").unwrap(), Some(b"1".to_vec()));
+        }
+    }
+    
+    #[test]
+    fn write_skew() {
+        for isolation in LEVELS {
+            // At least one of alice and bob has to stay on call
+            let db = open(&MemEnv::new());
+            db.put("alice", "on").unwrap();
+            db.put("bob", "on").unwrap();
+            let mut first = db.begin_with_isolation(isolation).unwrap();
+            let mut second = db.begin_with_isolation(isolation).unwrap();
+            for (txn, me) in [(&mut first, "alice"), (&mut second, "bob")] {
+                let on_call = ["alice", "bob"].iter()
+                    .filter(|name| txn.get(name).unwrap().is_some())
+                    .count();
+                if on_call == 2 {
+                    txn.delete(me);
+                }
+            }
+            first.commit().unwrap();
+            
+            let res = second.commit();
+            if isolation == IsolationLevel::Serializable {
+                assert_eq!(res.unwrap_err().kind(), ErrorKind::Conflict);
+                assert_eq!(db.get("bob").unwrap(), Some(b"on".to_vec()));
+            } else {
+                // Nobody is on call
+                res.unwrap();
+                assert_eq!(db.get("alice").unwrap(), None);
+                assert_eq!(db.get("bob").unwrap(), None);
+            }
+        }
+    }
+    
+    #[test]
+    fn transactions_do_not_see_ingested_data() {
+        let db = open(&MemEnv::new());
+        db.put("a", "1").unwrap();
+        let mut txn = db.begin().unwrap();
+        assert_eq!(txn.get("a").unwrap(), Some(b"1".to_vec()));
+        let records = vec![Ok(("a", "2")), Ok(("b", "2"))];
+        db.ingest(records).unwrap();
+        
+        assert_eq!(txn.get("a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(txn.get("b").unwrap(), None);
+        // Writing back what it read would lose the ingested value
+        txn.put("a", "3");
+        assert_eq!(txn.commit().unwrap_err().kind(), ErrorKind::Conflict);
+        assert_eq!(db.get("a").unwrap(), Some(b"2".to_vec()));
+    }
+    
+    #[test]
+    fn checkpoints_do_not_wait_for_open_transactions() {
+        let db = open(&MemEnv::new());
+        db.put("a", "1").unwrap();
+        let reader = db.begin().unwrap();
+        let mut writer = db.begin().unwrap();
+        writer.put("a", "2");
+        db.checkpoint().unwrap();
+        
+        assert_eq!(reader.get("a").unwrap(), Some(b"1".to_vec()));
+        writer.commit().unwrap();
+        db.checkpoint().unwrap();
+        assert_eq!(reader.get("a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get("a").unwrap(), Some(b"2".to_vec()));
+    }
+    
+    #[test]
+    fn transactions_past_their_timeout_are_aborted() {
+        use std::path::Path;
+        use crate::log::io::LogReader;
+        use crate::log::logentry::LogEntry;
+        
+        let env = MemEnv::new();
+        let options = DBOptions {
+            concurrency: Concurrency::Mvcc,
+            transaction_timeout: Some(Duration::from_millis(20)),
+            env: Arc::new(env.clone()),
+            ..DBOptions::default()
+        };
+        let db = DB::open_with_options("/thorkv", options).unwrap();
+        db.put("a", "1").unwrap();
+        let before = crate::types::now();
+        let mut stuck = db.begin().unwrap();
+        stuck.put("a", "2");
+        let mut patient = db.begin().unwrap();
+        patient.set_timeout(None);
+        assert!(stuck.started() >= before);
+        assert_eq!(stuck.state(), TransactionState::Active);
+        
+        thread::sleep(Duration::from_millis(30));
+        db.abort_expired();
+        assert_eq!(stuck.state(), TransactionState::Aborted);
+        assert_eq!(patient.state(), TransactionState::Active);
+        let states: Vec<_> = db.transactions()
+            .into_iter()
+            .map(|info| (info.xid, info.state))
+            .collect();
+        assert_eq!(states, vec![
+            (stuck.xid(), TransactionState::Aborted),
+            (patient.xid(), TransactionState::Active),
+        ]);
+        assert_eq!(db.stats().active_transactions, 1);
+        patient.commit().unwrap();
+        
+        // A checkpoint doesn't wait for it, it's aborted all the same
+        let mut late = db.begin().unwrap();
+        late.put("a", "3");
+        db.checkpoint().unwrap();
+        assert_eq!(late.state(), TransactionState::Active);
+        thread::sleep(Duration::from_millis(30));
+        db.abort_expired();
+        assert_eq!(late.state(), TransactionState::Aborted);
+        
+        assert_eq!(stuck.get("a").unwrap_err().kind(), ErrorKind::Aborted);
+        assert_eq!(stuck.commit().unwrap_err().kind(), ErrorKind::Aborted);
+        assert_eq!(db.get("a").unwrap(), Some(b"1".to_vec()));
+        let late_xid = late.xid();
+        drop(late);
+        assert!(db.transactions().is_empty());
+        drop(db);
+        
+        let log_path = Path::new("/thorkv").join(LOG_FILENAME);
+        let mut reader = LogReader::open_with_env(&env, &log_path).unwrap();
+        let mut aborted = vec![];
+        while let Some(log) = reader.read() {
+            if let LogEntry::XAbort { xid } = log {
+                aborted.push(xid);
+            }
+        }
+        assert_eq!(aborted.len(), 2);
+        assert_eq!(aborted[1], late_xid);
+        let db = open(&env);
+        assert_eq!(db.get("a").unwrap(), Some(b"1".to_vec()));
+    }
+    
+    #[test]
+    fn rollback_to_savepoint() {
+        use std::path::Path;
+        use crate::log::io::LogReader;
+        use crate::log::logentry::LogEntry;
+        
+        let env = MemEnv::new();
+        {
+            let db = open(&env);
+            db.put("a", "0").unwrap();
+            let mut txn = db.begin().unwrap();
+            txn.put("a", "1");
+            txn.savepoint("outer");
+            txn.put("a", "2");
+            txn.delete("b");
+            txn.savepoint("inner");
+            txn.put("c", "3");
+            
+            txn.rollback_to("inner").unwrap();
+            assert_eq!(txn.get("c").unwrap(), None);
+            assert_eq!(txn.get("a").unwrap(), Some(b"2".to_vec()));
+            txn.put("d", "4");
+            txn.rollback_to("outer").unwrap();
+            assert_eq!(txn.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(txn.get("d").unwrap(), None);
+            // Rolled back past
+            assert!(txn.rollback_to("inner").is_err());
+            assert!(txn.rollback_to("unknown").is_err());
+            txn.put("e", "5");
+            txn.commit().unwrap();
+        }
+        
+        let log_path = Path::new("/thorkv").join(LOG_FILENAME);
+        let mut reader = LogReader::open_with_env(&env, &log_path).unwrap();
+        let mut updated = vec![];
+        while let Some(log) = reader.read() {
+            if let LogEntry::Update { key, .. } = log {
+                updated.push(key);
+            }
+        }
+        assert_eq!(updated, vec![b"a".to_vec(), b"a".to_vec(), b"e".to_vec()]);
+        let db = open(&env);
+        let expected = vec![pair("a", "1"), pair("e", "5")];
+        let txn = db.begin().unwrap();
+        assert_eq!(txn.scan("").unwrap(), expected.into_iter().collect());
+    }
+    
+    #[test]
+    fn transactions_need_mvcc() {
+        let options = DBOptions {
+            env: Arc::new(MemEnv::new()),
+            ..DBOptions::default()
+        };
+        let db = DB::open_with_options("/thorkv", options).unwrap();
+        assert!(db.begin().is_err());
+    }
+    
+    #[test]
+    fn commits_alongside_checkpoints_are_recovered() {
+        let env = MemEnv::new();
+        {
+            let db = open(&env);
+            let stop = AtomicBool::new(false);
+            thread::scope(|s| {
+                s.spawn(|| {
+                    while !stop.load(Ordering::SeqCst) {
+                        db.checkpoint().unwrap();
+                    }
+                });
+                for i in 0..200 {
+                    let reader = db.begin().unwrap();
+                    let mut txn = db.begin().unwrap();
+                    let seen = txn.get("sum").unwrap();
+                    txn.put("sum", i.to_string());
+                    txn.put(format!("key{}", i), "1");
+                    txn.commit().unwrap();
+                    assert_eq!(reader.get("sum").unwrap(), seen);
+                }
+                stop.store(true, Ordering::SeqCst);
+            });
+        }
+        let db = open(&env);
+        assert_eq!(db.get("sum").unwrap(), Some(b"199".to_vec()));
+        assert_eq!(db.get("key150").unwrap(), Some(b"1".to_vec()));
+    }
+}
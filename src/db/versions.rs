@@ -0,0 +1,422 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::storage::KeyValueStorage;
+use crate::storage::lfmap::LFMapStorage;
+use crate::types::{CheckpointPhase, Error};
+use crate::util::sync::RwLock;
+
+pub(crate) type StorageRef = Arc<dyn KeyValueStorage + Send + Sync>;
+
+/// The live and stable version of every record, as CALC keeps them.
+///
+/// The live version is the current one. Commits after the point of
+/// consistency of a running checkpoint copy a record's live version to its
+/// stable version before the first change to it, so the checkpoint can still
+/// capture the record as it was at the point of consistency.
+///
+/// Snapshots work the same way, each open one keeps its own stable versions
+/// as of when it was taken.
+///
+/// A commit that writes several records keeps the versions before it the
+/// same way while it's applied, so `get` never returns part of it without
+/// waiting for it either.
+///
+/// Commits are applied one at a time, capturing and reading snapshots may
+/// run alongside them.
+pub(crate) struct Versions {
+    live: StorageRef,
+    stable: StableVersions,
+    // We need a graveyard to keep the set of keys that has been deleted
+    // on the live version but still alive on the stable version.
+    graveyard: KeySet,
+    snapshots: RwLock<Vec<Arc<StableVersions>>>,
+    // The versions before the multi-record commit being applied, if any
+    applying: RwLock<Option<Arc<StableVersions>>>,
+}
+
+impl Versions {
+    pub fn new(live: StorageRef, stable: StorageRef) -> Self {
+        Self {
+            live,
+            stable: StableVersions::new(stable),
+            graveyard: KeySet::new(),
+            snapshots: RwLock::new(Vec::new()),
+            applying: RwLock::new(None),
+        }
+    }
+    
+    pub fn live(&self) -> &StorageRef {
+        &self.live
+    }
+    
+    /// The latest committed version of key
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        // Read the live version first: if the commit that wrote it is still
+        // being applied, it saved the version before it by now.
+        let live_value = self.live.get(key);
+        let applying = self.applying.read().unwrap();
+        match applying.as_ref().and_then(|commit| commit.saved(key)) {
+            Some(value) => value,
+            None => live_value,
+        }
+    }
+    
+    /// Number of keys whose stable version is kept
+    pub fn stable_len(&self) -> usize {
+        self.stable.len()
+    }
+    
+    /// Apply a committed write to the live version in the given phase
+    pub fn apply(
+        &self,
+        phase: CheckpointPhase,
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) {
+        // Commits after the checkpoint's point of consistency have to keep
+        // the version the checkpoint captures around until it's on disk.
+        let after_consistency = phase == CheckpointPhase::RESOLVE
+            || phase == CheckpointPhase::CAPTURE;
+        if after_consistency {
+            self.stable.save(&*self.live, key);
+        }
+        for snapshot in self.snapshots.read().unwrap().iter() {
+            snapshot.save(&*self.live, key);
+        }
+        match value {
+            Some(value) => self.live.put(key, value),
+            None => {
+                if after_consistency && self.stable.has_value(key) {
+                    self.graveyard.insert(key);
+                }
+                self.live.delete(key);
+            }
+        }
+    }
+    
+    /// Apply the writes of a commit in the given phase. `get` sees either
+    /// all or none of them.
+    pub fn apply_all(
+        &self,
+        phase: CheckpointPhase,
+        writes: &[(&[u8], Option<&[u8]>)],
+    ) {
+        if let [(key, value)] = writes {
+            self.apply(phase, key, *value);
+            return;
+        }
+        let commit = self.open_snapshot();
+        *self.applying.write().unwrap() = Some(commit.clone());
+        for (key, value) in writes {
+            self.apply(phase, key, *value);
+        }
+        // The commit takes effect for get here
+        *self.applying.write().unwrap() = None;
+        self.close_snapshot(&commit);
+    }
+    
+    /// Call f with every record as of the point of consistency
+    pub fn capture<F>(&self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<(), Error>
+    {
+        // Live keys first: a key deleted after this is in the list, one
+        // deleted before is already in the graveyard.
+        let keys = self.live.keys();
+        let mut graveyard: HashSet<Vec<u8>> =
+            self.graveyard.keys().into_iter().collect();
+        for key in keys {
+            graveyard.remove(&key);
+            self.capture_key(&key, &mut f)?;
+        }
+        for key in graveyard {
+            self.capture_key(&key, &mut f)?;
+        }
+        Ok(())
+    }
+    
+    fn capture_key<F>(&self, key: &[u8], f: &mut F) -> Result<(), Error>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<(), Error>
+    {
+        match self.stable.get(&*self.live, key) {
+            Some(value) => f(key, &value),
+            None => Ok(()),
+        }
+    }
+    
+    /// Drop the stable versions once the checkpoint is on disk
+    pub fn clear_stable(&self) {
+        self.stable.clear();
+        for key in self.graveyard.keys() {
+            self.graveyard.remove(&key);
+        }
+    }
+    
+    /// Start keeping the versions as of now for a snapshot, until it's
+    /// closed. The caller keeps commits out meanwhile.
+    pub fn open_snapshot(&self) -> Arc<StableVersions> {
+        let values = Arc::new(LFMapStorage::new());
+        let snapshot = Arc::new(StableVersions::new(values));
+        self.snapshots.write().unwrap().push(snapshot.clone());
+        snapshot
+    }
+    
+    pub fn close_snapshot(&self, snapshot: &Arc<StableVersions>) {
+        let mut snapshots = self.snapshots.write().unwrap();
+        snapshots.retain(|open| !Arc::ptr_eq(open, snapshot));
+    }
+    
+    /// The version of key in snapshot
+    pub fn get_in(&self, snapshot: &StableVersions, key: &[u8])
+        -> Option<Vec<u8>>
+    {
+        snapshot.get(&*self.live, key)
+    }
+    
+    /// Every key that may have a version in snapshot, in no particular
+    /// order
+    pub fn keys_in(&self, snapshot: &StableVersions) -> Vec<Vec<u8>> {
+        // Live keys first, a key deleted after this is in the list, one
+        // deleted before has been saved in the snapshot.
+        let mut keys = self.live.keys();
+        let live_keys: HashSet<&Vec<u8>> = keys.iter().collect();
+        let saved: Vec<Vec<u8>> = snapshot.keys.keys()
+            .into_iter()
+            .filter(|key| !live_keys.contains(key))
+            .collect();
+        keys.extend(saved);
+        keys
+    }
+}
+
+/// The versions of records as of some point in time, for those that changed
+/// since. A record's live version is copied here before its first change
+/// after that point.
+pub(crate) struct StableVersions {
+    values: StorageRef,
+    // Keys saved so far, the ones without a value didn't exist at that
+    // point
+    keys: KeySet,
+}
+
+impl StableVersions {
+    fn new(values: StorageRef) -> Self {
+        Self {
+            values,
+            keys: KeySet::new(),
+        }
+    }
+    
+    // The stable version has to be in place before the live version changes,
+    // get relies on this ordering.
+    fn save(&self, live: &dyn KeyValueStorage, key: &[u8]) {
+        if self.keys.contains(key) {
+            return;
+        }
+        if let Some(value) = live.get(key) {
+            self.values.put(key, &value);
+        }
+        self.keys.insert(key);
+    }
+    
+    // The version of key at that point
+    fn get(&self, live: &dyn KeyValueStorage, key: &[u8]) -> Option<Vec<u8>> {
+        // Read the live version before looking for a stable one: a commit
+        // saves the stable version before changing the live one, so if the
+        // live version we read is too new the stable one is there.
+        let live_value = live.get(key);
+        if self.keys.contains(key) {
+            self.values.get(key)
+        } else {
+            live_value
+        }
+    }
+    
+    // The version saved for key, None if it hasn't been saved
+    fn saved(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        if self.keys.contains(key) {
+            Some(self.values.get(key))
+        } else {
+            None
+        }
+    }
+    
+    fn has_value(&self, key: &[u8]) -> bool {
+        self.values.get(key).is_some()
+    }
+    
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+    
+    fn clear(&self) {
+        for key in self.keys.keys() {
+            self.values.delete(&key);
+            self.keys.remove(&key);
+        }
+    }
+}
+
+// A concurrent set of keys. Lock-free, except under loom which can't see
+// inside lockfree's atomics.
+struct KeySet {
+    #[cfg(not(loom))]
+    keys: lockfree::set::Set<Vec<u8>>,
+    #[cfg(loom)]
+    keys: crate::util::sync::Mutex<HashSet<Vec<u8>>>,
+}
+
+#[cfg(not(loom))]
+impl KeySet {
+    fn new() -> Self {
+        Self { keys: lockfree::set::Set::new() }
+    }
+    
+    fn contains(&self, key: &[u8]) -> bool {
+        self.keys.contains(&key.to_vec())
+    }
+    
+    fn insert(&self, key: &[u8]) {
+        let _ = self.keys.insert(key.to_vec());
+    }
+    
+    fn remove(&self, key: &[u8]) {
+        self.keys.remove(&key.to_vec());
+    }
+    
+    fn keys(&self) -> Vec<Vec<u8>> {
+        self.keys.iter().map(|key| key.to_vec()).collect()
+    }
+    
+    fn len(&self) -> usize {
+        self.keys.iter().count()
+    }
+}
+
+#[cfg(loom)]
+impl KeySet {
+    fn new() -> Self {
+        Self { keys: Default::default() }
+    }
+    
+    fn contains(&self, key: &[u8]) -> bool {
+        self.keys.lock().unwrap().contains(key)
+    }
+    
+    fn insert(&self, key: &[u8]) {
+        self.keys.lock().unwrap().insert(key.to_vec());
+    }
+    
+    fn remove(&self, key: &[u8]) {
+        self.keys.lock().unwrap().remove(key);
+    }
+    
+    fn keys(&self) -> Vec<Vec<u8>> {
+        self.keys.lock().unwrap().iter().cloned().collect()
+    }
+    
+    fn len(&self) -> usize {
+        self.keys.lock().unwrap().len()
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+    use crate::util::sync::Mutex;
+    use loom::thread;
+    
+    // Live and stable storage loom can see into
+    #[derive(Default)]
+    struct MutexStorage(Mutex<HashMap<Vec<u8>, Vec<u8>>>);
+    
+    impl KeyValueStorage for MutexStorage {
+        fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+            self.0.lock().unwrap().get(key).cloned()
+        }
+        
+        fn put(&self, key: &[u8], value: &[u8]) {
+            self.0.lock().unwrap().insert(key.to_vec(), value.to_vec());
+        }
+        
+        fn delete(&self, key: &[u8]) {
+            self.0.lock().unwrap().remove(key);
+        }
+        
+        fn keys(&self) -> Vec<Vec<u8>> {
+            self.0.lock().unwrap().keys().cloned().collect()
+        }
+    }
+    
+    // Capture while a commit in CAPTURE phase applies writes, which must
+    // not show up in what is captured
+    fn check_capture(writes: &'static [(&'static str, Option<&'static str>)]) {
+        loom::model(move || {
+            let live = Arc::new(MutexStorage::default());
+            live.put(b"a", b"1");
+            let stable = Arc::new(MutexStorage::default());
+            let versions = loom::sync::Arc::new(Versions::new(live, stable));
+            let writer = {
+                let versions = versions.clone();
+                thread::spawn(move || {
+                    for (key, value) in writes {
+                        let value = value.map(str::as_bytes);
+                        let phase = CheckpointPhase::CAPTURE;
+                        versions.apply(phase, key.as_bytes(), value);
+                    }
+                })
+            };
+            
+            let mut captured = BTreeMap::new();
+            versions.capture(|key, value| {
+                captured.insert(key.to_vec(), value.to_vec());
+                Ok(())
+            }).unwrap();
+            let expected: BTreeMap<_, _> =
+                vec![(b"a".to_vec(), b"1".to_vec())].into_iter().collect();
+            assert_eq!(captured, expected);
+            
+            writer.join().unwrap();
+        });
+    }
+    
+    // Read a and then b while a commit writes both
+    #[test]
+    fn loom_reads_never_see_part_of_a_commit() {
+        loom::model(|| {
+            let live = Arc::new(MutexStorage::default());
+            live.put(b"a", b"1");
+            live.put(b"b", b"1");
+            let stable = Arc::new(MutexStorage::default());
+            let versions = loom::sync::Arc::new(Versions::new(live, stable));
+            let writer = {
+                let versions = versions.clone();
+                thread::spawn(move || {
+                    let writes: [(&[u8], Option<&[u8]>); 2] =
+                        [(b"a", Some(b"2")), (b"b", Some(b"2"))];
+                    versions.apply_all(CheckpointPhase::REST, &writes);
+                })
+            };
+            
+            let a = versions.get(b"a").unwrap();
+            let b = versions.get(b"b").unwrap();
+            // Reading a after the commit and b before it can't happen
+            assert!(!(a == b"2" && b == b"1"));
+            
+            writer.join().unwrap();
+        });
+    }
+    
+    #[test]
+    fn loom_capture_ignores_updates_and_inserts() {
+        check_capture(&[("a", Some("2")), ("b", Some("1"))]);
+    }
+    
+    #[test]
+    fn loom_capture_keeps_deleted_records() {
+        check_capture(&[("a", None)]);
+    }
+}
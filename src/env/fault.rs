@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::env::{Env, EnvLock, MemEnv, ReadableFile, WritableFile};
+use crate::types::Error;
+
+/// What survives of the data written to a file since its last sync
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Unsynced {
+    /// All of it is lost
+    Dropped,
+    /// All of it made it to disk anyway
+    Kept,
+    /// The first half made it, the rest is lost
+    Torn,
+}
+
+/// An in-memory filesystem that crashes at a chosen I/O operation.
+///
+/// It keeps track of what a crash would leave behind: file contents as of
+/// their last sync, and only the files and renames that a directory sync
+/// made durable. Every operation that changes a file or a directory counts;
+/// the one the crash happens at fails (a write that crashes writes half its
+/// data first) and so does everything after it. Directories always exist
+/// and locks always succeed.
+pub(crate) struct FaultEnv {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    // Operations performed so far
+    ops: u64,
+    crash_at: Option<u64>,
+    crashed: bool,
+    // What a reader would see now
+    names: HashMap<PathBuf, usize>,
+    // What a crash would leave behind
+    durable_names: HashMap<PathBuf, usize>,
+    // By file id
+    files: Vec<Contents>,
+}
+
+#[derive(Default)]
+struct Contents {
+    data: Vec<u8>,
+    durable: Vec<u8>,
+}
+
+impl State {
+    // Count an operation, returns true if the crash happens at this one
+    fn tick(&mut self) -> io::Result<bool> {
+        if self.crashed {
+            return Err(crashed());
+        }
+        self.ops += 1;
+        self.crashed = self.crash_at == Some(self.ops);
+        Ok(self.crashed)
+    }
+    
+    fn id(&self, path: &Path) -> io::Result<usize> {
+        self.names.get(path).copied().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "No such file")
+        })
+    }
+    
+    fn new_file(&mut self, path: &Path) -> usize {
+        self.files.push(Contents::default());
+        let id = self.files.len() - 1;
+        self.names.insert(path.to_path_buf(), id);
+        id
+    }
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("Simulated crash")
+}
+
+impl FaultEnv {
+    /// crash_at counts operations from 1, None never crashes
+    pub fn new(crash_at: Option<u64>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                ops: 0,
+                crash_at,
+                crashed: false,
+                names: HashMap::new(),
+                durable_names: HashMap::new(),
+                files: Vec::new(),
+            })),
+        }
+    }
+    
+    /// Number of operations performed so far
+    pub fn ops(&self) -> u64 {
+        self.state.lock().unwrap().ops
+    }
+    
+    /// The files a crash would leave behind
+    pub fn durable_state(&self, unsynced: Unsynced) -> io::Result<MemEnv> {
+        let env = MemEnv::new();
+        let state = self.state.lock().unwrap();
+        for (path, id) in &state.durable_names {
+            let contents = &state.files[*id];
+            let mut data = contents.durable.clone();
+            if contents.data.starts_with(&contents.durable) {
+                let tail = &contents.data[contents.durable.len()..];
+                match unsynced {
+                    Unsynced::Dropped => {}
+                    Unsynced::Kept => data.extend_from_slice(tail),
+                    Unsynced::Torn => {
+                        data.extend_from_slice(&tail[..tail.len() / 2])
+                    }
+                }
+            }
+            if let Some(dir) = path.parent() {
+                env.create_dir_all(dir)?;
+            }
+            env.create(path)?.write_all(&data)?;
+        }
+        Ok(env)
+    }
+}
+
+impl Env for FaultEnv {
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadableFile>> {
+        let state = self.state.lock().unwrap();
+        let data = state.files[state.id(path)?].data.clone();
+        Ok(Box::new(Cursor::new(data)))
+    }
+    
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock().unwrap();
+        if state.tick()? {
+            return Err(crashed());
+        }
+        let id = match state.names.get(path) {
+            Some(id) => *id,
+            None => state.new_file(path),
+        };
+        Ok(Box::new(FaultFile { state: self.state.clone(), id }))
+    }
+    
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock().unwrap();
+        if state.tick()? {
+            return Err(crashed());
+        }
+        let id = state.new_file(path);
+        Ok(Box::new(FaultFile { state: self.state.clone(), id }))
+    }
+    
+    fn exists(&self, path: &Path) -> bool {
+        self.state.lock().unwrap().names.contains_key(path)
+    }
+    
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.id(path)?;
+        if state.tick()? {
+            return Err(crashed());
+        }
+        let contents = &mut state.files[id];
+        contents.data.truncate(len as usize);
+        contents.durable = contents.data.clone();
+        Ok(())
+    }
+    
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.tick()? {
+            return Err(crashed());
+        }
+        let id = state.id(from)?;
+        state.names.remove(from);
+        state.names.insert(to.to_path_buf(), id);
+        Ok(())
+    }
+    
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.id(path)?;
+        if state.tick()? {
+            return Err(crashed());
+        }
+        state.names.remove(path);
+        Ok(())
+    }
+    
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.lock().unwrap();
+        let mut paths: Vec<PathBuf> = state.names.keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+    
+    fn create_dir_all(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+    
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.tick()? {
+            return Err(crashed());
+        }
+        let state = &mut *state;
+        let in_dir = |path: &PathBuf| path.parent() == Some(dir);
+        state.durable_names.retain(|path, _| !in_dir(path));
+        for (path, id) in &state.names {
+            if in_dir(path) {
+                state.durable_names.insert(path.clone(), *id);
+            }
+        }
+        Ok(())
+    }
+    
+    fn lock(&self, _dir: &Path, _exclusive: bool) -> Result<EnvLock, Error> {
+        Ok(Box::new(()))
+    }
+}
+
+struct FaultFile {
+    state: Arc<Mutex<State>>,
+    id: usize,
+}
+
+impl Write for FaultFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.tick()? {
+            // Torn write
+            state.files[self.id].data.extend_from_slice(&buf[..buf.len() / 2]);
+            return Err(crashed());
+        }
+        state.files[self.id].data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WritableFile for FaultFile {
+    fn sync(&mut self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.tick()? {
+            return Err(crashed());
+        }
+        let contents = &mut state.files[self.id];
+        contents.durable = contents.data.clone();
+        Ok(())
+    }
+}
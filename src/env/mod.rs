@@ -0,0 +1,354 @@
+//! The filesystem as the database sees it.
+//!
+//! Every file the database reads or writes, and the directory lock, goes
+//! through an `Env`. `RealEnv` is the local filesystem and the default.
+//! `MemEnv` keeps everything in memory, which makes for hermetic tests and
+//! for running ThorKV as a pure in-memory cache: nothing touches the disk
+//! and everything is lost when the process exits. Set `DBOptions::env` to
+//! pick one.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::db::lock::DirLock;
+use crate::types::Error;
+
+#[cfg(test)]
+pub(crate) mod fault;
+
+/// Held while a database directory is locked, see `Env::lock`
+pub type EnvLock = Box<dyn Send + Sync>;
+
+pub trait Env: Send + Sync {
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadableFile>>;
+    /// Open a file for appending, creating it if it doesn't exist
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+    /// Create an empty file, truncating it if it exists
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+    fn exists(&self, path: &Path) -> bool;
+    /// Cut a file down to len bytes and sync it. A file that is no longer
+    /// than that is left alone.
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn delete(&self, path: &Path) -> io::Result<()>;
+    /// Paths of the entries in dir
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+    /// Make renames and newly created files inside dir durable
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+    /// Lock a database directory, exclusively for a read-write instance or
+    /// shared for read-only ones. The lock is held until the returned value
+    /// is dropped.
+    fn lock(&self, dir: &Path, exclusive: bool) -> Result<EnvLock, Error>;
+}
+
+pub trait ReadableFile: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadableFile for T {}
+
+/// A file being written. What's written is only durable after `sync`.
+pub trait WritableFile: Write + Send {
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// The local filesystem
+pub struct RealEnv;
+
+impl Env for RealEnv {
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadableFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+    
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+    
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+    
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+    
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        if file.metadata()?.len() > len {
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+    
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+    
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+    
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+    
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+    
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+    
+    fn lock(&self, dir: &Path, exclusive: bool) -> Result<EnvLock, Error> {
+        let lock = if exclusive {
+            DirLock::exclusive(dir)?
+        } else {
+            DirLock::shared(dir)?
+        };
+        Ok(Box::new(lock))
+    }
+}
+
+impl WritableFile for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+/// Files kept in memory. Syncing does nothing and nothing outlives the
+/// process.
+///
+/// Clones share the same files. A file opened for reading sees its contents
+/// as of when it was opened.
+#[derive(Clone, Default)]
+pub struct MemEnv {
+    state: Arc<Mutex<MemState>>,
+}
+
+#[derive(Default)]
+struct MemState {
+    files: HashMap<PathBuf, Arc<Mutex<Vec<u8>>>>,
+    dirs: HashSet<PathBuf>,
+    // Number of shared locks on each locked directory, -1 if exclusive
+    locks: HashMap<PathBuf, i64>,
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+impl MemState {
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => {
+                if self.dirs.contains(dir) {
+                    Ok(())
+                } else {
+                    Err(not_found(dir))
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+    
+    fn file(&self, path: &Path) -> io::Result<Arc<Mutex<Vec<u8>>>> {
+        self.files.get(path).cloned().ok_or_else(|| not_found(path))
+    }
+}
+
+impl MemEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Env for MemEnv {
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadableFile>> {
+        let data = self.state.lock().unwrap().file(path)?;
+        let data = data.lock().unwrap().clone();
+        Ok(Box::new(Cursor::new(data)))
+    }
+    
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock().unwrap();
+        state.check_parent(path)?;
+        let data = state.files.entry(path.to_path_buf())
+            .or_default()
+            .clone();
+        Ok(Box::new(MemFile { data }))
+    }
+    
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock().unwrap();
+        state.check_parent(path)?;
+        // Writers of a file this replaces keep writing to the old one, like
+        // with an unlinked file
+        let data = Arc::new(Mutex::new(Vec::new()));
+        state.files.insert(path.to_path_buf(), data.clone());
+        Ok(Box::new(MemFile { data }))
+    }
+    
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state.lock().unwrap();
+        state.files.contains_key(path) || state.dirs.contains(path)
+    }
+    
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let data = self.state.lock().unwrap().file(path)?;
+        let mut data = data.lock().unwrap();
+        if data.len() as u64 > len {
+            data.truncate(len as usize);
+        }
+        Ok(())
+    }
+    
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_parent(to)?;
+        let data = state.files.remove(from).ok_or_else(|| not_found(from))?;
+        state.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+    
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.files.remove(path).map(|_| ()).ok_or_else(|| not_found(path))
+    }
+    
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.lock().unwrap();
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        let in_dir = |path: &&PathBuf| path.parent() == Some(dir);
+        let mut paths: Vec<PathBuf> = state.files.keys()
+            .chain(state.dirs.iter())
+            .filter(in_dir)
+            .cloned()
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+    
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for ancestor in dir.ancestors() {
+            if !ancestor.as_os_str().is_empty() {
+                state.dirs.insert(ancestor.to_path_buf());
+            }
+        }
+        Ok(())
+    }
+    
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+    
+    fn lock(&self, dir: &Path, exclusive: bool) -> Result<EnvLock, Error> {
+        let mut state = self.state.lock().unwrap();
+        let holders = state.locks.entry(dir.to_path_buf()).or_insert(0);
+        if *holders < 0 || (*holders > 0 && exclusive) {
+            return Err(Error::new(format!(
+                "Database {} is locked by another instance",
+                dir.display()
+            )));
+        }
+        *holders = if exclusive { -1 } else { *holders + 1 };
+        Ok(Box::new(MemLock {
+            state: self.state.clone(),
+            dir: dir.to_path_buf(),
+        }))
+    }
+}
+
+struct MemFile {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WritableFile for MemFile {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct MemLock {
+    state: Arc<Mutex<MemState>>,
+    dir: PathBuf,
+}
+
+impl Drop for MemLock {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        let holders = state.locks.get_mut(&self.dir).unwrap();
+        *holders = if *holders < 0 { 0 } else { *holders - 1 };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn mem_env_files() {
+        let env = MemEnv::new();
+        let dir = Path::new("/db");
+        let path = dir.join("wal.log");
+        assert!(env.open_append(&path).is_err());
+        env.create_dir_all(dir).unwrap();
+        
+        let mut file = env.open_append(&path).unwrap();
+        file.write_all(b"foo").unwrap();
+        let mut reader = env.open_read(&path).unwrap();
+        file.write_all(b"bar").unwrap();
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "foo");
+        
+        env.truncate(&path, 4).unwrap();
+        env.rename(&path, &dir.join("old.log")).unwrap();
+        assert!(!env.exists(&path));
+        assert_eq!(env.list(dir).unwrap(), vec![dir.join("old.log")]);
+        let mut data = Vec::new();
+        env.open_read(&dir.join("old.log")).unwrap()
+            .read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"foob");
+        env.delete(&dir.join("old.log")).unwrap();
+        assert_eq!(env.list(dir).unwrap(), Vec::<PathBuf>::new());
+        
+        let lock = env.lock(dir, true).unwrap();
+        assert!(env.lock(dir, false).is_err());
+        drop(lock);
+        let _shared = env.lock(dir, false).unwrap();
+        let _shared2 = env.lock(dir, false).unwrap();
+        assert!(env.lock(dir, true).is_err());
+    }
+}
@@ -1,9 +1,12 @@
-mod checkpoint;
-mod constants;
-mod log;
-mod storage;
+mod recovery;
 mod transaction;
-mod types;
-mod util;
 
+pub mod checkpoint;
+pub mod constants;
 pub mod db;
+pub mod env;
+pub mod log;
+pub mod metrics;
+pub mod storage;
+pub mod types;
+pub mod util;
@@ -1,16 +1,19 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Cursor, Read, Write};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
 
-use crate::log::logentry::LogEntry;
+use byteorder::ReadBytesExt;
+
+use crate::env::{Env, ReadableFile, RealEnv, WritableFile};
+use crate::log::logentry::{LogEntry, LogEntryRef};
+use crate::types::{Error, ErrorKind, Lsn};
 use crate::util::serde;
-use crate::util::serde::Serialize;
+use crate::util::serde::{FormatVersion, Serialize, LEGACY_USIZE_LEN};
 
-// TODO: 
-// [ ] Batched log writer
+// TODO:
 // [ ] Batched log reader
 
 /// Encapsulates writing LogRecord to disk
-/// 
+///
 /// Log format
 ///
 ///  ----------------------------------------------------------
@@ -19,15 +22,27 @@ use crate::util::serde::Serialize;
 ///
 /// Explanation:
 /// The write always write the type of the log struct before the other member
-/// of the log struct itself. This way the reader know the boundary of the 
+/// of the log struct itself. This way the reader know the boundary of the
 /// next log entry to read.
 ///
-/// When serializing log record to file, whenever there is a choice between 
+/// When serializing log record to file, whenever there is a choice between
 /// big-endian and little-endian, we always choose big-endian.
 ///
+/// Each log entry is framed as
+///
+///  ---------------------------------------
+/// | version (u8) | size (varint) | entry |
+///  ---------------------------------------
+///
+/// where version is the serde::FormatVersion the entry is encoded with.
+/// Frames written before the version byte existed start with a native usize
+/// size instead (V1). The size is big-endian and nowhere near 2^56 so its
+/// first byte is always 0, which is how the reader tells the two apart. Old
+/// and new frames can be mixed in one file, so an existing log keeps working
+/// and new entries are simply appended in the current format.
+///
 pub struct LogWriter {
-    log_filepath: String,
-    file: File,
+    file: Box<dyn WritableFile>,
 }
 
 impl LogWriter {
@@ -37,83 +52,269 @@ impl LogWriter {
     }
     
     pub fn with_path(log_filepath: String) -> Self {
-        let file = OpenOptions::new() 
-            .append(true)
-            .create(true)
-            .open(&log_filepath)
-            .unwrap();
-        Self {
-            log_filepath,
-            file 
-        }
+        Self::open(Path::new(&log_filepath)).unwrap()
+    }
+    
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        Self::open_with_env(&RealEnv, path)
+    }
+    
+    pub fn open_with_env(env: &dyn Env, path: &Path)
+        -> std::io::Result<Self>
+    {
+        let file = env.open_append(path)?;
+        Ok(Self { file })
     }
     
     pub fn write(&mut self, log: &LogEntry) -> std::io::Result<()> {
-        let mut res = Vec::new();
-        let buf = log.serialize();
-        serde::serialize_u8_vec(&mut res, &buf);
-        self.file.write_all(&res)?;
-        Ok(())
+        self.write_bytes(&encode(log))
+    }
+    
+    /// Write a log entry that has already been framed with `encode`
+    pub fn write_bytes(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(buf)
     }
     
-    pub fn flush(&self) {
-        self.file.sync_data();
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.file.sync()
     }
 }
 
-const USIZE_LEN: usize = std::mem::size_of::<usize>();
+impl Default for LogWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Frame a log entry the way it's stored in the log file: the format
+/// version, the size of the serialized entry and the entry itself.
+pub fn encode(log: &LogEntry) -> Vec<u8> {
+    let size = log.serialized_len();
+    let mut res = Vec::with_capacity(1 + serde::MAX_VARINT_LEN + size);
+    res.push(serde::CURRENT_VERSION as u8);
+    serde::serialize_usize(&mut res, size);
+    log.serialize_into(&mut res);
+    res
+}
 
 /// Encapsulates reading LogRecord from disk
-struct LogReader {
-    log_filepath: String,
-    file: File
+pub struct LogReader {
+    file: BufReader<Box<dyn ReadableFile>>,
+    file_len: u64,
+    // Offset just past the last log entry read
+    offset: Lsn,
+    // Holds the last entry read, read_ref borrows from it
+    buf: Vec<u8>,
 }
 
 impl LogReader {
-    fn new() -> Self {
+    pub fn new() -> Self {
         // TODO: Don't hardcode log path
         Self::with_path(String::from("wal.log"))
     }
     
-    fn with_path(log_filepath: String) -> Self {
-        let file = File::open(&log_filepath).unwrap();
-        Self {
-            log_filepath,
-            file,
-        }
+    pub fn with_path(log_filepath: String) -> Self {
+        Self::open(Path::new(&log_filepath)).unwrap()
+    }
+    
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        Self::open_with_env(&RealEnv, path)
+    }
+    
+    pub fn open_with_env(env: &dyn Env, path: &Path)
+        -> std::io::Result<Self>
+    {
+        let mut file = env.open_read(path)?;
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            file: BufReader::new(file),
+            file_len,
+            offset: 0,
+            buf: Vec::new(),
+        })
+    }
+    
+    /// Skip to the log entry starting at the given LSN
+    pub fn seek(&mut self, lsn: Lsn) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(lsn))?;
+        self.offset = lsn;
+        Ok(())
+    }
+    
+    /// Size of the log file
+    pub fn len(&self) -> u64 {
+        self.file_len
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.file_len == 0
+    }
+    
+    /// LSN just past the last log entry read
+    pub fn offset(&self) -> Lsn {
+        self.offset
+    }
+    
+    /// Read the next log record, returning None if EOF is reached or the
+    /// next record is incomplete (a torn write at the end of the log).
+    pub fn read(&mut self) -> Option<LogEntry> {
+        self.read_ref().map(LogEntryRef::into_owned)
+    }
+    
+    /// Like read, but the entry borrows its keys and values from the
+    /// reader's buffer, which is reused by the next call.
+    pub fn read_ref(&mut self) -> Option<LogEntryRef<'_>> {
+        self.try_read_ref().ok().flatten()
     }
     
-    /// Read the next log record, returning None if EOF is reached
-    fn read(&mut self) -> Option<LogEntry> {
-        let mut size_buf: [u8; USIZE_LEN] = [0; USIZE_LEN];
-        self.file.read_exact(&mut size_buf);
-        let size: usize = serde::deserialize_usize(
-            &mut Cursor::new(&size_buf)
-        );
+    /// Like read_ref, but tells the end of the log apart from a bad entry.
+    ///
+    /// Returns None at the end of the file and an error if the next entry
+    /// is incomplete or can't be decoded. The offset is left at the start
+    /// of the bad entry.
+    pub fn try_read_ref(&mut self) -> Result<Option<LogEntryRef<'_>>, Error> {
+        if self.offset >= self.file_len {
+            return Ok(None);
+        }
+        let offset = self.offset;
+        let incomplete = || {
+            Error::with_kind(
+                ErrorKind::Incomplete,
+                format!("Incomplete log entry at offset {}", offset),
+            )
+        };
+        let first = self.file.read_u8().map_err(|_| incomplete())?;
+        let version;
+        let frame_header_len;
+        let size: u64;
+        if first == 0 {
+            // V1 frame, first is the most significant byte of the size
+            let mut size_buf = [0u8; LEGACY_USIZE_LEN];
+            self.file.read_exact(&mut size_buf[1..])
+                .map_err(|_| incomplete())?;
+            version = FormatVersion::V1;
+            frame_header_len = LEGACY_USIZE_LEN;
+            size = serde::deserialize_legacy_usize(
+                &mut Cursor::new(&size_buf)
+            )? as u64;
+        } else {
+            version = FormatVersion::from_u8(first).ok_or_else(|| {
+                Error::new(format!(
+                    "Unknown log entry version {} at offset {}",
+                    first, offset
+                ))
+            })?;
+            size = serde::read_varint(&mut self.file)
+                .map_err(|_| incomplete())?;
+            frame_header_len = 1 + serde::varint_len(size);
+        }
+        let end = offset
+            .saturating_add(frame_header_len as u64)
+            .saturating_add(size);
+        if end > self.file_len {
+            // Leave the file where the entry starts so that the offset
+            // still matches it
+            self.file.seek(SeekFrom::Start(offset))?;
+            return Err(incomplete());
+        }
         
-        let mut struct_buf = vec![0u8; size];
-        self.file.read_exact(&mut struct_buf);
-        let res = LogEntry::deserialize(&struct_buf);
-        res
+        self.buf.resize(size as usize, 0);
+        self.file.read_exact(&mut self.buf).map_err(|_| incomplete())?;
+        match serde::from_bytes(&self.buf, version) {
+            Ok(log) => {
+                self.offset = end;
+                Ok(Some(log))
+            }
+            Err(err) => Err(Error::new(format!(
+                "Corrupt log entry at offset {}: {}",
+                offset, err
+            ))),
+        }
+    }
+    
+    /// Whether a committed transaction is logged somewhere past the offset,
+    /// after try_read_ref found an incomplete entry there.
+    ///
+    /// A torn write only cuts off the end of the log, so if one is, the
+    /// entry is corrupt rather than torn and truncating the log there would
+    /// lose the commit. Stray bytes of a torn entry rarely decode as a
+    /// commit, anything else that follows doesn't count.
+    pub fn commit_follows(&mut self) -> Result<bool, Error> {
+        let offset = self.offset;
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut tail = Vec::new();
+        self.file.read_to_end(&mut tail)?;
+        self.file.seek(SeekFrom::Start(offset))?;
+        Ok((1..tail.len()).any(|start| commits_to_end(&tail[start..])))
     }
 }
 
-fn serialize_log_record(log: &LogEntry) -> &[u8] {
-    let bytes = unsafe { any_as_u8_slice(log) };
-    bytes
+// Whether bytes hold well-formed entries up to where they end or are cut
+// short, at least one of which is a commit
+fn commits_to_end(mut bytes: &[u8]) -> bool {
+    let mut committed = false;
+    loop {
+        match decode_frame(bytes) {
+            Ok(Some((log, len))) => {
+                committed |= matches!(log, LogEntryRef::XCommit { .. });
+                bytes = &bytes[len..];
+            }
+            Ok(None) => return committed,
+            Err(_) => return false,
+        }
+    }
+}
+
+// Decode the frame bytes start with, along with its length. Returns None if
+// it runs past the end of bytes.
+fn decode_frame(bytes: &[u8])
+    -> Result<Option<(LogEntryRef<'_>, usize)>, Error>
+{
+    let mut rdr = Cursor::new(bytes);
+    let (version, size) = match bytes.first() {
+        None => return Ok(None),
+        Some(0) => {
+            if bytes.len() < LEGACY_USIZE_LEN {
+                return Ok(None);
+            }
+            let size = serde::deserialize_legacy_usize(&mut rdr)?;
+            (FormatVersion::V1, size as u64)
+        }
+        Some(&first) => {
+            let version = FormatVersion::from_u8(first).ok_or_else(|| {
+                Error::new(format!("Unknown log entry version {}", first))
+            })?;
+            rdr.set_position(1);
+            match serde::read_varint(&mut rdr) {
+                Ok(size) => (version, size),
+                Err(err)
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(None);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    };
+    let start = rdr.position() as usize;
+    let end = (start as u64).saturating_add(size);
+    if end > bytes.len() as u64 {
+        return Ok(None);
+    }
+    let log = serde::from_bytes(&bytes[start..end as usize], version)?;
+    Ok(Some((log, end as usize)))
 }
 
-unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
-    ::std::slice::from_raw_parts(
-        (p as *const T) as *const u8,
-        ::std::mem::size_of::<T>(),
-    )
+impl Default for LogReader {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::log::logentry::LogicalOperation;
     
     #[test]
     fn write_and_read_log() {
@@ -126,14 +327,85 @@ mod tests {
             },
         ];
         
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wal.log");
+        
         {
-            let mut writer = LogWriter::new();
+            let mut writer = LogWriter::open(&path).unwrap();
             writer.write(&logs[0]).unwrap();
-            writer.flush();
+            writer.flush().unwrap();
         }
         
-        let mut reader = LogReader::new();
+        let mut reader = LogReader::open(&path).unwrap();
         let log1 = reader.read().unwrap();
         assert_eq!(log1, logs[0]);
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn read_legacy_frames() {
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-legacy-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wal.log");
+        
+        // XBegin { xid: 7 } framed the way it was before the version byte
+        let mut legacy = Vec::new();
+        legacy.extend_from_slice(&9usize.to_be_bytes());
+        legacy.push(1);
+        legacy.extend_from_slice(&7u64.to_be_bytes());
+        std::fs::write(&path, &legacy).unwrap();
+        
+        {
+            let mut writer = LogWriter::open(&path).unwrap();
+            let commit = LogEntry::XCommit { xid: 7, timestamp: 1 };
+            writer.write(&commit).unwrap();
+        }
+        
+        let mut reader = LogReader::open(&path).unwrap();
+        assert_eq!(reader.read(), Some(LogEntry::XBegin { xid: 7 }));
+        assert_eq!(reader.offset(), legacy.len() as Lsn);
+        let commit = LogEntry::XCommit { xid: 7, timestamp: 1 };
+        assert_eq!(reader.read(), Some(commit));
+        assert_eq!(reader.offset(), reader.len());
+        assert_eq!(reader.read(), None);
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn read_reports_bad_entries() {
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-bad-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wal.log");
+        
+        let mut log = encode(&LogEntry::XBegin { xid: 1 });
+        let corrupt_at = log.len() as Lsn;
+        // Frame holding an unknown entry type
+        log.extend_from_slice(&[serde::CURRENT_VERSION as u8, 1, 0xff]);
+        std::fs::write(&path, &log).unwrap();
+        let mut reader = LogReader::open(&path).unwrap();
+        assert!(reader.try_read_ref().unwrap().is_some());
+        let err = reader.try_read_ref().unwrap_err();
+        assert!(err.to_string().starts_with("Corrupt log entry"));
+        assert_eq!(reader.offset(), corrupt_at);
+        
+        // Torn write, the frame is cut short
+        log.truncate(corrupt_at as usize);
+        let commit = encode(&LogEntry::XCommit { xid: 1, timestamp: 1 });
+        log.extend_from_slice(&commit[..commit.len() - 1]);
+        std::fs::write(&path, &log).unwrap();
+        let mut reader = LogReader::open(&path).unwrap();
+        assert!(reader.try_read_ref().unwrap().is_some());
+        let err = reader.try_read_ref().unwrap_err();
+        assert!(err.to_string().starts_with("Incomplete log entry"));
+        assert_eq!(reader.offset(), corrupt_at);
+        assert!(reader.read().is_none());
+        
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
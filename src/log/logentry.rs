@@ -1,19 +1,21 @@
-use std::convert::{TryFrom, TryInto};
+use std::convert::TryFrom;
 use std::io::Cursor;
 
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ReadBytesExt};
 
-use crate::types::{CheckpointPhase, Xid};
+use crate::types::{CheckpointPhase, Error, Timestamp, Xid};
 use crate::util::serde;
-use crate::util::serde::Serialize;
+use crate::util::serde::{Deserialize, FormatVersion, Serialize};
 
 enum LogEntryType {
-    XBEGIN = 1,
-    XCOMMIT,
-    XABORT,
-    UPDATE,
+    XBegin = 1,
+    XCommit,
+    XAbort,
+    Update,
     // Tracks the current checkpointing phase
-    CPHASE,
+    CPhase,
+    // Redo-only update, see LogicalOperation
+    Operation,
 }
 
 impl TryFrom<u8> for LogEntryType {
@@ -21,20 +23,23 @@ impl TryFrom<u8> for LogEntryType {
     
     fn try_from(v: u8) -> Result<Self, Self::Error> {
         match v {
-            x if x == Self::XBEGIN as u8  => Ok(Self::XBEGIN),
-            x if x == Self::XCOMMIT as u8 => Ok(Self::XCOMMIT),
-            x if x == Self::XABORT as u8  => Ok(Self::XABORT),
-            x if x == Self::UPDATE as u8  => Ok(Self::UPDATE),
-            x if x == Self::CPHASE as u8  => Ok(Self::CPHASE),
+            x if x == Self::XBegin as u8  => Ok(Self::XBegin),
+            x if x == Self::XCommit as u8 => Ok(Self::XCommit),
+            x if x == Self::XAbort as u8  => Ok(Self::XAbort),
+            x if x == Self::Update as u8  => Ok(Self::Update),
+            x if x == Self::CPhase as u8  => Ok(Self::CPhase),
+            x if x == Self::Operation as u8 => Ok(Self::Operation),
             _ => Err(()),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum LogEntry {
     XBegin { xid: Xid },
-    XCommit { xid: Xid },
+    // timestamp is when the transaction committed, 0 for commits logged
+    // before V3
+    XCommit { xid: Xid, timestamp: Timestamp },
     XAbort { xid: Xid },
     Update { 
         xid: Xid, 
@@ -43,107 +48,319 @@ pub enum LogEntry {
         previous_value: Option<Vec<u8>>,
     },
     CPhase(CheckpointPhase),
+    // Same as Update without the before-image, written in redo-only mode
+    Operation { xid: Xid, op: LogicalOperation },
 }
 
 impl LogEntry {
-    // TODO: This should return none if it cannot read the next record size
-    pub fn deserialize(bytes: &[u8]) -> Option<LogEntry> {
-        let mut rdr = Cursor::new(bytes);
-        let lr_type: u8;
-        match rdr.read_u8() {
-            Ok(t) => lr_type = t,
-            _     => return None,
+    /// Number of bytes serialize_into appends
+    pub fn serialized_len(&self) -> usize {
+        const TYPE_LEN: usize = 1;
+        const XID_LEN: usize = 8;
+        const TIMESTAMP_LEN: usize = 8;
+        const FLAG_LEN: usize = 1;
+        let u8_vec_len = |data: &[u8]| {
+            serde::varint_len(data.len() as u64) + data.len()
+        };
+        let option_len = |data: &Option<Vec<u8>>| {
+            FLAG_LEN + data.as_ref().map_or(0, |data| u8_vec_len(data))
+        };
+        match self {
+            Self::XBegin { .. } | Self::XAbort { .. } => TYPE_LEN + XID_LEN,
+            Self::XCommit { .. } => TYPE_LEN + XID_LEN + TIMESTAMP_LEN,
+            Self::Update { key, value, previous_value, .. } => {
+                TYPE_LEN + XID_LEN + u8_vec_len(key)
+                    + option_len(value) + option_len(previous_value)
+            }
+            Self::CPhase(_) => TYPE_LEN + 1,
+            Self::Operation { op, .. } => {
+                TYPE_LEN + XID_LEN + u8_vec_len(op.key()) + FLAG_LEN
+                    + op.value().map_or(0, u8_vec_len)
+            }
         }
-        
-        let lr_type: LogEntryType = lr_type.try_into().unwrap();
-        let mut log = None;
-        match lr_type {
-            LogEntryType::XBEGIN    => {
-                let xid = serde::deserialize_xid(&mut rdr);
-                log = Some(LogEntry::XBegin { xid });
+    }
+}
+
+/// Borrowed view of a LogEntry, keys and values point into the buffer the
+/// entry was decoded from. Used on the read path to avoid copying every key
+/// and value out of the log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogEntryRef<'a> {
+    XBegin { xid: Xid },
+    XCommit { xid: Xid, timestamp: Timestamp },
+    XAbort { xid: Xid },
+    Update {
+        xid: Xid,
+        key: &'a [u8],
+        value: Option<&'a [u8]>,
+        previous_value: Option<&'a [u8]>,
+    },
+    CPhase(CheckpointPhase),
+    Operation { xid: Xid, key: &'a [u8], value: Option<&'a [u8]> },
+}
+
+impl<'a> Deserialize<'a> for LogEntryRef<'a> {
+    fn deserialize(rdr: &mut Cursor<&'a [u8]>, version: FormatVersion)
+        -> Result<Self, Error>
+    {
+        let lr_type = rdr.read_u8()?;
+        let lr_type = LogEntryType::try_from(lr_type).map_err(|_| {
+            Error::new(format!("Unknown log entry type: {}", lr_type))
+        })?;
+        let log = match lr_type {
+            LogEntryType::XBegin    => {
+                let xid = serde::deserialize_xid(rdr)?;
+                LogEntryRef::XBegin { xid }
             }
-            LogEntryType::XCOMMIT   => {
-                let xid = serde::deserialize_xid(&mut rdr);
-                log = Some(LogEntry::XCommit { xid });
+            LogEntryType::XCommit   => {
+                let xid = serde::deserialize_xid(rdr)?;
+                let timestamp = match version {
+                    FormatVersion::V1 | FormatVersion::V2 => 0,
+                    FormatVersion::V3 => rdr.read_u64::<BigEndian>()?,
+                };
+                LogEntryRef::XCommit { xid, timestamp }
             }
-            LogEntryType::XABORT    => {
-                let xid = serde::deserialize_xid(&mut rdr);
-                log = Some(LogEntry::XAbort { xid });
+            LogEntryType::XAbort    => {
+                let xid = serde::deserialize_xid(rdr)?;
+                LogEntryRef::XAbort { xid }
             }
-            LogEntryType::UPDATE    => {
-                let xid = serde::deserialize_xid(&mut rdr);
-                let key = serde::deserialize_u8_vec(&mut rdr);
-                let value;
-                if rdr.read_u8().unwrap() == 1 {
-                    value = Some(serde::deserialize_u8_vec(&mut rdr));
-                } else {
-                    value = None;
-                }
-                let previous_value;
-                if rdr.read_u8().unwrap() == 1 {
-                    previous_value = Some(serde::deserialize_u8_vec(&mut rdr));
-                } else {
-                    previous_value = None;
-                }
-                log = Some(LogEntry::Update { xid, key, value, previous_value });
+            LogEntryType::Update    => {
+                let xid = serde::deserialize_xid(rdr)?;
+                let key = serde::deserialize_u8_slice(rdr, version)?;
+                let value = deserialize_option(rdr, version)?;
+                let previous_value = deserialize_option(rdr, version)?;
+                LogEntryRef::Update { xid, key, value, previous_value }
             },
-            LogEntryType::CPHASE    => {
-                let phase_u8 = rdr.read_u8().unwrap();
-                let phase = CheckpointPhase::try_from(phase_u8).unwrap();
-                log = Some(LogEntry::CPhase(phase));
+            LogEntryType::CPhase    => {
+                let phase = CheckpointPhase::deserialize(rdr, version)?;
+                LogEntryRef::CPhase(phase)
+            }
+            LogEntryType::Operation => {
+                let xid = serde::deserialize_xid(rdr)?;
+                let key = serde::deserialize_u8_slice(rdr, version)?;
+                let value = deserialize_option(rdr, version)?;
+                LogEntryRef::Operation { xid, key, value }
+            }
+        };
+        Ok(log)
+    }
+}
+
+impl<'a> Deserialize<'a> for LogEntry {
+    fn deserialize(rdr: &mut Cursor<&'a [u8]>, version: FormatVersion)
+        -> Result<Self, Error>
+    {
+        Ok(LogEntryRef::deserialize(rdr, version)?.into_owned())
+    }
+}
+
+// A present flag followed by the bytes if it's set
+fn deserialize_option<'a>(
+    rdr: &mut Cursor<&'a [u8]>,
+    version: FormatVersion,
+) -> Result<Option<&'a [u8]>, Error> {
+    match rdr.read_u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(serde::deserialize_u8_slice(rdr, version)?)),
+        flag => Err(Error::new(format!("Invalid option flag: {}", flag))),
+    }
+}
+
+impl<'a> LogEntryRef<'a> {
+    pub fn into_owned(self) -> LogEntry {
+        match self {
+            Self::XBegin { xid } => LogEntry::XBegin { xid },
+            Self::XCommit { xid, timestamp } => {
+                LogEntry::XCommit { xid, timestamp }
+            }
+            Self::XAbort { xid } => LogEntry::XAbort { xid },
+            Self::Update { xid, key, value, previous_value } => {
+                LogEntry::Update {
+                    xid,
+                    key: key.to_vec(),
+                    value: value.map(|v| v.to_vec()),
+                    previous_value: previous_value.map(|v| v.to_vec()),
+                }
+            }
+            Self::CPhase(phase) => LogEntry::CPhase(phase),
+            Self::Operation { xid, key, value } => {
+                let key = key.to_vec();
+                let op = match value {
+                    Some(value) => {
+                        LogicalOperation::Set { key, value: value.to_vec() }
+                    }
+                    None => LogicalOperation::Delete { key },
+                };
+                LogEntry::Operation { xid, op }
             }
         }
-        log
     }
 }
 
 impl Serialize for LogEntry {
-    fn serialize(&self) -> Vec<u8> {
-        let mut res = Vec::new();
+    fn serialize_into(&self, res: &mut Vec<u8>) {
+        res.reserve(self.serialized_len());
         match &self {
             Self::XBegin { xid } => {
-                res.write_u8(LogEntryType::XBEGIN as u8).unwrap();
-                serde::serialize_xid(&mut res, xid);
+                res.push(LogEntryType::XBegin as u8);
+                serde::serialize_xid(res, xid);
             },
-            Self::XCommit { xid } => {
-                res.write_u8(LogEntryType::XCOMMIT as u8).unwrap();
-                serde::serialize_xid(&mut res, xid);
+            Self::XCommit { xid, timestamp } => {
+                res.push(LogEntryType::XCommit as u8);
+                serde::serialize_xid(res, xid);
+                serde::serialize_u64(res, *timestamp);
             },
             Self::XAbort { xid } => {
-                res.write_u8(LogEntryType::XABORT as u8).unwrap();
-                serde::serialize_xid(&mut res, xid);
+                res.push(LogEntryType::XAbort as u8);
+                serde::serialize_xid(res, xid);
             },
             Self::Update { xid, key, value, previous_value } => {
-                res.write_u8(LogEntryType::UPDATE as u8).unwrap();
-                serde::serialize_xid(&mut res, xid);
-                serde::serialize_u8_vec(&mut res, key);
+                res.push(LogEntryType::Update as u8);
+                serde::serialize_xid(res, xid);
+                serde::serialize_u8_vec(res, key);
                 if value.is_some() {
-                    res.write_u8(1).unwrap();
-                    serde::serialize_u8_vec(&mut res, value.as_ref().unwrap());
+                    res.push(1);
+                    serde::serialize_u8_vec(res, value.as_ref().unwrap());
                 } else {
-                    res.write_u8(0).unwrap();
+                    res.push(0);
                 }
                 if previous_value.is_some() {
-                    res.write_u8(1).unwrap();
+                    res.push(1);
                     serde::serialize_u8_vec(
-                        &mut res, 
+                        res,
                         previous_value.as_ref().unwrap()
                     );
                 } else {
-                    res.write_u8(0).unwrap();
+                    res.push(0);
                 }
             }
             Self::CPhase(phase) => {
-                res.write_u8(LogEntryType::CPHASE as u8).unwrap();
-                res.write_u8(*phase as u8).unwrap();
+                res.push(LogEntryType::CPhase as u8);
+                phase.serialize_into(res);
+            }
+            Self::Operation { xid, op } => {
+                res.push(LogEntryType::Operation as u8);
+                serde::serialize_xid(res, xid);
+                match op {
+                    LogicalOperation::Set { key, value } => {
+                        serde::serialize_u8_vec(res, key);
+                        res.push(1);
+                        serde::serialize_u8_vec(res, value);
+                    }
+                    LogicalOperation::Delete { key } => {
+                        serde::serialize_u8_vec(res, key);
+                        res.push(0);
+                    }
+                }
             }
         }
-        res
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// The effect of an update on a single key, without the value it replaced.
+#[derive(Clone, Debug, PartialEq)]
 pub enum LogicalOperation {
-    Set { key: String, value: String },
-    Delete { key: String },
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+impl LogicalOperation {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Self::Set { key, .. } => key,
+            Self::Delete { key } => key,
+        }
+    }
+    
+    /// The new value, None for a delete
+    pub fn value(&self) -> Option<&[u8]> {
+        match self {
+            Self::Set { value, .. } => Some(value),
+            Self::Delete { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::serde::CURRENT_VERSION;
+    use proptest::collection::vec;
+    use proptest::option;
+    use proptest::prelude::*;
+    
+    fn bytes() -> impl Strategy<Value = Vec<u8>> {
+        vec(any::<u8>(), 0..64)
+    }
+    
+    fn phase() -> impl Strategy<Value = CheckpointPhase> {
+        prop_oneof![
+            Just(CheckpointPhase::REST),
+            Just(CheckpointPhase::PREPARE),
+            Just(CheckpointPhase::RESOLVE),
+            Just(CheckpointPhase::CAPTURE),
+            Just(CheckpointPhase::COMPLETE),
+        ]
+    }
+    
+    fn log_entry() -> impl Strategy<Value = LogEntry> {
+        let op = prop_oneof![
+            (bytes(), bytes())
+                .prop_map(|(key, value)| LogicalOperation::Set { key, value }),
+            bytes().prop_map(|key| LogicalOperation::Delete { key }),
+        ];
+        prop_oneof![
+            any::<Xid>().prop_map(|xid| LogEntry::XBegin { xid }),
+            (any::<Xid>(), any::<Timestamp>()).prop_map(|(xid, timestamp)| {
+                LogEntry::XCommit { xid, timestamp }
+            }),
+            any::<Xid>().prop_map(|xid| LogEntry::XAbort { xid }),
+            (any::<Xid>(), bytes(), option::of(bytes()), option::of(bytes()))
+                .prop_map(|(xid, key, value, previous_value)| {
+                    LogEntry::Update { xid, key, value, previous_value }
+                }),
+            phase().prop_map(LogEntry::CPhase),
+            (any::<Xid>(), op).prop_map(|(xid, op)| {
+                LogEntry::Operation { xid, op }
+            }),
+        ]
+    }
+    
+    proptest! {
+        #[test]
+        fn log_entry_round_trip(log in log_entry()) {
+            let bytes = log.serialize();
+            prop_assert_eq!(bytes.len(), log.serialized_len());
+            let log_out: LogEntry =
+                serde::from_bytes(&bytes, CURRENT_VERSION).unwrap();
+            prop_assert_eq!(log_out, log);
+        }
+        
+        #[test]
+        fn truncated_log_entry_is_an_error(
+            log in log_entry(),
+            cut in any::<prop::sample::Index>(),
+        ) {
+            let bytes = log.serialize();
+            let bytes = &bytes[..cut.index(bytes.len())];
+            let res: Result<LogEntry, _> =
+                serde::from_bytes(bytes, CURRENT_VERSION);
+            prop_assert!(res.is_err());
+        }
+        
+        #[test]
+        fn arbitrary_bytes_do_not_panic(bytes in vec(any::<u8>(), 0..64)) {
+            let _: Result<LogEntryRef, _> =
+                serde::from_bytes(&bytes, CURRENT_VERSION);
+        }
+    }
+    
+    #[test]
+    fn v2_commit_has_no_timestamp() {
+        let mut bytes = vec![LogEntryType::XCommit as u8];
+        bytes.extend_from_slice(&7u64.to_be_bytes());
+        let log: LogEntry =
+            serde::from_bytes(&bytes, FormatVersion::V2).unwrap();
+        assert_eq!(log, LogEntry::XCommit { xid: 7, timestamp: 0 });
+    }
 }
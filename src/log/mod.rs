@@ -1,39 +1,296 @@
 use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
 
+use tokio::sync::oneshot;
+use tracing::{error, trace_span, warn};
+
+use crate::env::Env;
 use crate::log::io::LogWriter;
 use crate::log::logentry::LogEntry;
+use crate::metrics::Metrics;
+use crate::types::{Error, Lsn};
 
-mod io;
-mod logentry;
+pub mod io;
+pub mod logentry;
 
-const BATCH_SIZE: u32 = 32;
+/// Appends log entries to the WAL.
+///
+/// Callers append entries to an in-memory queue and get back the LSN just
+/// past their last entry. A background flusher thread writes whatever has
+/// accumulated in the queue and fsyncs it in one go (group commit), so
+/// concurrent committers share a single fsync. `wait_durable` blocks until
+/// the flusher has synced past a given LSN, `durable` does the same without
+/// blocking the calling thread. The flusher is a plain thread, neither needs
+/// a tokio runtime.
+pub struct LogManager {
+    shared: Arc<Shared>,
+    flusher: Mutex<Option<JoinHandle<()>>>,
+}
 
-struct LogManager {
-    writer: LogWriter,
-    log_queue: VecDeque<LogEntry>,
+struct Shared {
+    state: Mutex<LogState>,
+    // Signalled when entries are queued or the log is closed
+    queued: Condvar,
+    // Signalled when durable_lsn moves forward or the flusher fails
+    flushed: Condvar,
+}
+
+struct LogState {
+    // Framed log entries that haven't been written to the file yet
+    log_queue: VecDeque<Vec<u8>>,
+    // LSN just past the last entry in log_queue
+    appended_lsn: Lsn,
+    // Everything before this LSN has been written and fsynced
+    durable_lsn: Lsn,
+    closed: bool,
+    // Set if the flusher failed to write or sync, the log is unusable after
+    error: Option<String>,
+    // Async callers waiting for an LSN to become durable
+    waiters: Vec<(Lsn, oneshot::Sender<Result<(), String>>)>,
 }
 
 impl LogManager {
-    pub fn new() -> Self {
-        Self {
-            writer: LogWriter::new(),
-            log_queue: VecDeque::new(),
+    /// Open the log file for appending, starting at the given LSN (the size
+    /// of the valid part of the log as determined by recovery). Writes and
+    /// fsyncs are recorded in metrics.
+    pub fn open(
+        env: &dyn Env,
+        path: &Path,
+        lsn: Lsn,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, Error> {
+        let writer = LogWriter::open_with_env(env, path)?;
+        // The log may have just been created
+        if let Some(dir) = path.parent() {
+            env.sync_dir(dir)?;
         }
+        let shared = Arc::new(Shared {
+            state: Mutex::new(LogState {
+                log_queue: VecDeque::new(),
+                appended_lsn: lsn,
+                durable_lsn: lsn,
+                closed: false,
+                error: None,
+                waiters: Vec::new(),
+            }),
+            queued: Condvar::new(),
+            flushed: Condvar::new(),
+        });
+        
+        let flusher_shared = shared.clone();
+        let flusher = thread::Builder::new()
+            .name(String::from("thorkv-log-flusher"))
+            .spawn(move || run_flusher(flusher_shared, writer, metrics))?;
+        
+        Ok(Self {
+            shared,
+            flusher: Mutex::new(Some(flusher)),
+        })
     }
     
-    /// Start a thread that process redo record in batches and flush to disk
-    pub async fn start(&self) {
-        tokio::spawn(async {
-            // TODO: Currently this write each log record in sync which isn't 
-            // efficient. Perhaps try to batch log records?
-            
-            // First, copy and clear log_queue
-        });
+    /// Queue log entries, returning the LSN just past the last one.
+    ///
+    /// The entries are written contiguously, entries appended by other
+    /// threads never end up in between them.
+    pub fn append(&self, logs: &[LogEntry]) -> Result<Lsn, Error> {
+        let frames: Vec<Vec<u8>> = logs.iter().map(io::encode).collect();
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(err) = &state.error {
+            return Err(Error::new(format!("Log is unusable: {}", err)));
+        }
+        if state.closed {
+            return Err(Error::new("Log is closed"));
+        }
+        for frame in frames {
+            state.appended_lsn += frame.len() as Lsn;
+            state.log_queue.push_back(frame);
+        }
+        self.shared.queued.notify_one();
+        Ok(state.appended_lsn)
+    }
+    
+    /// Everything before this LSN has been written and fsynced. It's always
+    /// at a boundary between the groups of entries passed to append.
+    pub fn durable_lsn(&self) -> Lsn {
+        self.shared.state.lock().unwrap().durable_lsn
+    }
+    
+    /// Block until everything before lsn has been fsynced.
+    pub fn wait_durable(&self, lsn: Lsn) -> Result<(), Error> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if state.durable_lsn >= lsn {
+                return Ok(());
+            }
+            if let Some(err) = &state.error {
+                return Err(Error::new(format!("Log is unusable: {}", err)));
+            }
+            state = self.shared.flushed.wait(state).unwrap();
+        }
+    }
+    
+    /// Wait until everything before lsn has been fsynced, without blocking.
+    pub async fn durable(&self, lsn: Lsn) -> Result<(), Error> {
+        let rx = {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.durable_lsn >= lsn {
+                return Ok(());
+            }
+            if let Some(err) = &state.error {
+                return Err(Error::new(format!("Log is unusable: {}", err)));
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiters.push((lsn, tx));
+            rx
+        };
+        match rx.await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => {
+                Err(Error::new(format!("Log is unusable: {}", err)))
+            }
+            Err(_) => Err(Error::new("Log flusher stopped")),
+        }
+    }
+    
+    /// Append a single entry and wait for it to be durable.
+    pub fn append_log(&self, log: LogEntry) -> Result<(), Error> {
+        let lsn = self.append(&[log])?;
+        self.wait_durable(lsn)
+    }
+    
+    /// Stop accepting entries, write and fsync everything still queued and
+    /// stop the flusher thread.
+    pub fn close(&self) -> Result<(), Error> {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.closed = true;
+            self.shared.queued.notify_one();
+        }
+        if let Some(flusher) = self.flusher.lock().unwrap().take() {
+            flusher.join()
+                .map_err(|_| Error::new("Log flusher thread panicked"))?;
+        }
+        let state = self.shared.state.lock().unwrap();
+        match &state.error {
+            Some(err) => Err(Error::new(format!("Log is unusable: {}", err))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for LogManager {
+    fn drop(&mut self) {
+        if let Err(err) = self.close() {
+            warn!(%err, "Failed to close the log");
+        }
+    }
+}
+
+fn run_flusher(
+    shared: Arc<Shared>,
+    mut writer: LogWriter,
+    metrics: Arc<Metrics>,
+) {
+    loop {
+        // Take everything queued so far
+        let (batch, lsn) = {
+            let mut state = shared.state.lock().unwrap();
+            while state.log_queue.is_empty() && !state.closed {
+                state = shared.queued.wait(state).unwrap();
+            }
+            if state.log_queue.is_empty() {
+                return;
+            }
+            let batch: Vec<Vec<u8>> = state.log_queue.drain(..).collect();
+            (batch, state.appended_lsn)
+        };
+        
+        let bytes: usize = batch.iter().map(Vec::len).sum();
+        let span = trace_span!("wal_flush", entries = batch.len(), bytes, lsn);
+        let _guard = span.enter();
+        let res = batch.iter()
+            .try_for_each(|frame| writer.write_bytes(frame))
+            .and_then(|_| {
+                let start = Instant::now();
+                writer.flush()?;
+                metrics.fsync_latency.observe_duration(start.elapsed());
+                Ok(())
+            });
+        
+        let mut state = shared.state.lock().unwrap();
+        match res {
+            Ok(()) => {
+                metrics.wal_bytes.add(bytes as u64);
+                metrics.fsyncs.inc();
+                metrics.flush_entries.observe(batch.len() as u64);
+                state.durable_lsn = lsn;
+                let waiters = std::mem::take(&mut state.waiters);
+                for (waiter_lsn, tx) in waiters {
+                    if waiter_lsn <= lsn {
+                        let _ = tx.send(Ok(()));
+                    } else {
+                        state.waiters.push((waiter_lsn, tx));
+                    }
+                }
+            }
+            Err(err) => {
+                error!(%err, "Failed to write the log, it is unusable now");
+                state.error = Some(err.to_string());
+                state.closed = true;
+                state.log_queue.clear();
+                for (_, tx) in state.waiters.drain(..) {
+                    let _ = tx.send(Err(err.to_string()));
+                }
+                shared.flushed.notify_all();
+                return;
+            }
+        }
+        shared.flushed.notify_all();
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::RealEnv;
+    use crate::log::io::LogReader;
     
-    pub fn append_log(&mut self, log: LogEntry) {
-        self.writer.write(&log).unwrap();
-        self.writer.flush();
-        //self.log_queue.push_back(log);
+    #[test]
+    fn close_drains_queued_entries() {
+        let dir = std::env::temp_dir()
+            .join(format!("thorkv-logmanager-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wal.log");
+        
+        let logs: Vec<LogEntry> = (1..=100)
+            .map(|xid| LogEntry::XCommit { xid, timestamp: xid })
+            .collect();
+        {
+            let metrics = Arc::new(Metrics::new());
+            let log_manager =
+                LogManager::open(&RealEnv, &path, 0, metrics.clone())
+                    .unwrap();
+            for log in &logs {
+                log_manager.append(std::slice::from_ref(log)).unwrap();
+            }
+            log_manager.close().unwrap();
+            assert!(log_manager.append(&[logs[0].clone()]).is_err());
+            let bytes: usize = logs.iter().map(|l| io::encode(l).len()).sum();
+            assert_eq!(metrics.wal_bytes.get(), bytes as u64);
+            assert!(metrics.fsyncs.get() >= 1);
+            assert_eq!(metrics.flush_entries.snapshot().count,
+                       metrics.fsyncs.get());
+        }
+        
+        let mut reader = LogReader::open(&path).unwrap();
+        for log in &logs {
+            assert_eq!(&reader.read().unwrap(), log);
+        }
+        assert!(reader.read().is_none());
+        
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,4 +1,119 @@
-fn main() {
-    let db = thorkv::db::DB::open("db");
-    db.put("user_id", "1").unwrap();
+use std::env;
+use std::process;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info_span, Instrument};
+use tracing_subscriber::EnvFilter;
+
+use thorkv::db::{DB, DBRef};
+
+// Where the Prometheus metrics are served, override with THORKV_METRICS_ADDR
+const DEFAULT_METRICS_ADDR: &str = "127.0.0.1:9464";
+
+// What gets logged, override with THORKV_LOG using the env_logger style
+// filter syntax, e.g. "debug" or "info,thorkv::recovery=debug"
+const DEFAULT_LOG_FILTER: &str = "info";
+
+#[tokio::main]
+async fn main() {
+    init_logging();
+    let db = DB::open("db").unwrap();
+    db.put_async("user_id", "1").await.unwrap();
+
+    let addr = env::var("THORKV_METRICS_ADDR")
+        .unwrap_or_else(|_| String::from(DEFAULT_METRICS_ADDR));
+    let listener = TcpListener::bind(&addr).await.unwrap();
+    tokio::spawn(serve_metrics(listener, db.clone()));
+
+    wait_for_shutdown_signal().await;
+    db.close().unwrap();
+}
+
+/// Log to stderr, filtered by THORKV_LOG and formatted as plain text or, with
+/// THORKV_LOG_FORMAT=json, as one JSON object per line.
+fn init_logging() {
+    let filter = env::var("THORKV_LOG")
+        .unwrap_or_else(|_| String::from(DEFAULT_LOG_FILTER));
+    let filter = EnvFilter::try_new(&filter).unwrap_or_else(|err| {
+        eprintln!("Invalid THORKV_LOG {:?}: {}", filter, err);
+        process::exit(2);
+    });
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match env::var("THORKV_LOG_FORMAT").as_deref() {
+        Ok("json") => builder.json().init(),
+        Ok("text") | Err(_) => builder.init(),
+        Ok(format) => {
+            eprintln!("Invalid THORKV_LOG_FORMAT {:?}, expected text or json",
+                      format);
+            process::exit(2);
+        }
+    }
+}
+
+/// Answer `GET /metrics` with `DB::stats` in the Prometheus text format
+async fn serve_metrics(listener: TcpListener, db: DBRef) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                debug!(%err, "Failed to accept a connection");
+                continue;
+            }
+        };
+        let db = db.clone();
+        let span = info_span!("request", %peer);
+        tokio::spawn(async move {
+            if let Err(err) = handle_metrics_request(stream, &db).await {
+                debug!(%err, "Failed to answer request");
+            }
+        }.instrument(span));
+    }
+}
+
+async fn handle_metrics_request(mut stream: TcpStream, db: &DB)
+    -> std::io::Result<()>
+{
+    // Only the request line matters, the rest of the request is ignored
+    let mut buf = vec![0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let mut parts = request.split_whitespace();
+    let method = parts.next();
+    let path = parts.next();
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            db.stats().to_prometheus(),
+        ),
+        _ => ("404 Not Found", "text/plain", String::from("Not Found\n")),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    );
+    debug!(method = method.unwrap_or(""), path = path.unwrap_or(""), status,
+           "Answered request");
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = sigterm.recv() => {},
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() {
+    tokio::signal::ctrl_c().await.unwrap();
 }
@@ -0,0 +1,182 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::types::CheckpointPhase;
+
+/// Bucket upper bounds for latencies, in microseconds
+pub const LATENCY_BUCKETS: &[u64] = &[
+    10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000,
+    1_000_000, 5_000_000, 30_000_000,
+];
+
+/// Bucket upper bounds for sizes and counts
+pub const SIZE_BUCKETS: &[u64] = &[
+    1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024, 4096,
+];
+
+/// A count that only goes up
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+    
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+    
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Distribution of observed values over fixed buckets.
+///
+/// Recording is a couple of relaxed atomic adds, so it's cheap enough for
+/// every operation. A snapshot taken while values are recorded may be
+/// slightly inconsistent, e.g. count may not match the buckets yet.
+pub struct Histogram {
+    // Ascending upper bounds, values above the last one fall in an implicit
+    // +Inf bucket
+    bounds: &'static [u64],
+    // One per bound plus +Inf, not cumulative
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [u64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+    
+    pub fn observe(&self, v: u64) {
+        let i = self.bounds.iter()
+            .position(|bound| v <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(v, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Record a latency in microseconds
+    pub fn observe_duration(&self, d: Duration) {
+        self.observe(d.as_micros() as u64);
+    }
+    
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self.bounds.iter()
+            .zip(&self.buckets)
+            .map(|(bound, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                (*bound, cumulative)
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            sum: self.sum.load(Ordering::Relaxed),
+            count: self.count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of a Histogram
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HistogramSnapshot {
+    /// Upper bound of each bucket and the number of values up to it,
+    /// cumulative like Prometheus buckets. The +Inf bucket is count.
+    pub buckets: Vec<(u64, u64)>,
+    pub sum: u64,
+    pub count: u64,
+}
+
+/// Everything a DB records about itself, see `DB::stats`
+pub struct Metrics {
+    pub get_latency: Histogram,
+    pub put_latency: Histogram,
+    pub delete_latency: Histogram,
+    pub write_latency: Histogram,
+    pub wal_bytes: Counter,
+    pub fsyncs: Counter,
+    pub fsync_latency: Histogram,
+    // Entries written per fsync, the group commit batch size
+    pub flush_entries: Histogram,
+    pub checkpoints: Counter,
+    // Time spent in each phase but REST, indexed by phase_index
+    phase_durations: Vec<Histogram>,
+}
+
+pub const CHECKPOINT_PHASES: [CheckpointPhase; 4] = [
+    CheckpointPhase::PREPARE,
+    CheckpointPhase::RESOLVE,
+    CheckpointPhase::CAPTURE,
+    CheckpointPhase::COMPLETE,
+];
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            get_latency: Histogram::new(LATENCY_BUCKETS),
+            put_latency: Histogram::new(LATENCY_BUCKETS),
+            delete_latency: Histogram::new(LATENCY_BUCKETS),
+            write_latency: Histogram::new(LATENCY_BUCKETS),
+            wal_bytes: Counter::default(),
+            fsyncs: Counter::default(),
+            fsync_latency: Histogram::new(LATENCY_BUCKETS),
+            flush_entries: Histogram::new(SIZE_BUCKETS),
+            checkpoints: Counter::default(),
+            phase_durations: CHECKPOINT_PHASES.iter()
+                .map(|_| Histogram::new(LATENCY_BUCKETS))
+                .collect(),
+        }
+    }
+    
+    /// Time spent in a checkpoint phase, None for REST
+    pub fn phase_duration(&self, phase: CheckpointPhase)
+        -> Option<&Histogram>
+    {
+        CHECKPOINT_PHASES.iter()
+            .position(|p| *p == phase)
+            .map(|i| &self.phase_durations[i])
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Resident set size of this process, only available on Linux
+pub fn resident_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    // VmRSS:     1234 kB
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&[1, 10]);
+        for v in &[0, 1, 5, 10, 11, 100] {
+            histogram.observe(*v);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.buckets, vec![(1, 2), (10, 4)]);
+        assert_eq!(snapshot.count, 6);
+        assert_eq!(snapshot.sum, 127);
+    }
+}
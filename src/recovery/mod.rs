@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use tracing::{debug, info, info_span, warn};
+
+use crate::checkpoint::io::CheckpointReader;
+use crate::constants::{CHECKPOINT_FILENAME, LOG_FILENAME};
+use crate::env::Env;
+use crate::log::io::LogReader;
+use crate::log::logentry::LogEntryRef;
+use crate::storage::KeyValueStorage;
+use crate::types::{Error, ErrorKind, Lsn, Timestamp, Xid};
+
+// Writes of the transactions that haven't committed yet, by xid. A None
+// value is a delete.
+type PendingWrites = HashMap<Xid, Vec<(Vec<u8>, Option<Vec<u8>>)>>;
+
+// Report replay progress every this many log entries
+const PROGRESS_INTERVAL: u64 = 100_000;
+
+/// Where point-in-time recovery stops replaying the log
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecoveryTarget {
+    /// Stop right before the given transaction commits, it and every
+    /// transaction that commits after it are dropped
+    BeforeXid(Xid),
+    /// Replay the transactions that committed at or before the given time
+    /// and drop the rest. Commits logged before V3 have no timestamp and
+    /// are always replayed.
+    Time(Timestamp),
+}
+
+impl RecoveryTarget {
+    // Whether replay stops at the commit of xid at timestamp
+    fn stops_at(&self, xid: Xid, timestamp: Timestamp) -> bool {
+        match *self {
+            Self::BeforeXid(target) => xid == target,
+            Self::Time(target) => timestamp > target,
+        }
+    }
+}
+
+/// Rebuild the database content in storage from the last checkpoint and the
+/// WAL in dir, read through env.
+///
+/// The checkpoint is loaded first, then every transaction that committed
+/// after the checkpoint's point of consistency is replayed from the log.
+/// Transactions without an XCommit at the end of the log never finished
+/// committing and are ignored.
+///
+/// With a target, replay stops at the target instead of the end of the log.
+/// It's an error if the checkpoint already includes the target or if the
+/// target transaction isn't in the log.
+///
+/// Returns the LSN where the valid part of the log ends, anything after it
+/// is a torn write or was dropped to reach the target. A corrupt entry
+/// anywhere else fails recovery rather than dropping what follows it.
+pub fn recover(
+    env: &dyn Env,
+    dir: &Path,
+    storage: &dyn KeyValueStorage,
+    target: Option<RecoveryTarget>,
+) -> Result<Lsn, Error> {
+    let span = info_span!("recovery", dir = %dir.display(), ?target);
+    let _guard = span.enter();
+    let mut lsn = 0;
+    let checkpoint_path = dir.join(CHECKPOINT_FILENAME);
+    if env.exists(&checkpoint_path) {
+        let mut reader =
+            CheckpointReader::open_with_env(env, &checkpoint_path)?;
+        lsn = reader.header().lsn;
+        let mut records: u64 = 0;
+        while let Some(record) = reader.read_ref()? {
+            storage.put(record.key, record.value);
+            records += 1;
+        }
+        info!(records, lsn, "Loaded checkpoint");
+    }
+    
+    let log_path = dir.join(LOG_FILENAME);
+    if !env.exists(&log_path) {
+        if lsn > 0 {
+            return Err(Error::new(format!(
+                "{} is missing, cannot recover past the checkpoint",
+                log_path.display()
+            )));
+        }
+        return Ok(0);
+    }
+    
+    let mut reader = LogReader::open_with_env(env, &log_path)?;
+    if lsn > reader.len() {
+        return Err(Error::new(format!(
+            "{} is shorter than the checkpoint position {}",
+            log_path.display(),
+            lsn
+        )));
+    }
+    if let Some(target) = target {
+        check_not_checkpointed(&mut reader, lsn, target)?;
+    }
+    reader.seek(lsn)?;
+    
+    let mut pending: PendingWrites = HashMap::new();
+    let mut valid_end = lsn;
+    let mut reached_target = false;
+    let mut entries: u64 = 0;
+    let mut committed: u64 = 0;
+    let mut torn = false;
+    // Only the keys and new values are copied out of the log, before-images
+    // are skipped
+    loop {
+        let log = match reader.try_read_ref() {
+            Ok(Some(log)) => log,
+            Ok(None) => break,
+            // Checked below, it's only a torn write if it ends the log
+            Err(err) if err.kind() == ErrorKind::Incomplete => {
+                torn = true;
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        match log {
+            LogEntryRef::XBegin { xid } => {
+                pending.insert(xid, vec![]);
+            }
+            LogEntryRef::Update { xid, key, value, .. }
+            | LogEntryRef::Operation { xid, key, value } => {
+                let write = (key.to_vec(), value.map(|v| v.to_vec()));
+                pending.entry(xid).or_default().push(write);
+            }
+            LogEntryRef::XCommit { xid, timestamp } => {
+                if target.is_some_and(|t| t.stops_at(xid, timestamp)) {
+                    // valid_end is still where this transaction begins
+                    reached_target = true;
+                    break;
+                }
+                committed += 1;
+                let writes = pending.remove(&xid).unwrap_or_default();
+                for (key, value) in writes {
+                    match value {
+                        Some(value) => storage.put(&key, &value),
+                        None => storage.delete(&key),
+                    }
+                }
+            }
+            LogEntryRef::XAbort { xid } => {
+                pending.remove(&xid);
+            }
+            LogEntryRef::CPhase(_) => {}
+        }
+        if pending.is_empty() {
+            valid_end = reader.offset();
+        }
+        entries += 1;
+        if entries.is_multiple_of(PROGRESS_INTERVAL) {
+            debug!(entries, offset = reader.offset(), len = reader.len(),
+                   "Replaying log");
+        }
+    }
+    if torn && reader.commit_follows()? {
+        return Err(Error::new(format!(
+            "Log entry at offset {} is corrupt, committed transactions \
+             follow it",
+            reader.offset()
+        )));
+    }
+    info!(entries, committed, end = valid_end, "Replayed log");
+    if valid_end < reader.len() && !reached_target {
+        warn!(
+            dropped = reader.len() - valid_end,
+            "Log ends with an incomplete transaction or a torn write"
+        );
+    }
+    if let Some(RecoveryTarget::BeforeXid(xid)) = target {
+        if !reached_target {
+            return Err(Error::new(format!(
+                "Transaction {} doesn't commit after the checkpoint",
+                xid
+            )));
+        }
+    }
+    Ok(valid_end)
+}
+
+// Fail if a transaction committed before the checkpoint's position is past
+// the target, the checkpoint contains it and it can't be undone
+fn check_not_checkpointed(
+    reader: &mut LogReader,
+    lsn: Lsn,
+    target: RecoveryTarget,
+) -> Result<(), Error> {
+    reader.seek(0)?;
+    while reader.offset() < lsn {
+        match reader.try_read_ref()? {
+            Some(LogEntryRef::XCommit { xid, timestamp }) => {
+                if target.stops_at(xid, timestamp) {
+                    return Err(Error::new(format!(
+                        "The checkpoint already includes {:?}",
+                        target
+                    )));
+                }
+            }
+            Some(_) => {}
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+/// Cut off whatever follows the valid part of the log so that new entries
+/// are appended right after the last complete transaction.
+pub fn truncate_log(env: &dyn Env, dir: &Path, lsn: Lsn)
+    -> Result<(), Error>
+{
+    let log_path = dir.join(LOG_FILENAME);
+    if !env.exists(&log_path) {
+        return Ok(());
+    }
+    env.truncate(&log_path, lsn)?;
+    Ok(())
+}
@@ -12,11 +12,15 @@ impl LFMapStorage {
     }
 }
 
+impl Default for LFMapStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl KeyValueStorage for LFMapStorage {
     fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        let v = self.map.get(key);
-        let value = v.map(|x| x.clone().1);
-        value
+        self.map.get(key).map(|x| x.clone().1)
     }
     
     fn put(&self, key: &[u8], value: &[u8])  {
@@ -26,7 +30,7 @@ impl KeyValueStorage for LFMapStorage {
     fn delete(&self, key: &[u8]) {
         self.map.remove(key);
     }
-
+    
     fn keys(&self) -> Vec<Vec<u8>> {
         let mut keys = vec![];
         let mut iter = self.map.iter();
@@ -36,7 +40,7 @@ impl KeyValueStorage for LFMapStorage {
                 break;
             }
             let item = item.unwrap();
-            keys.push(item.1.clone());
+            keys.push(item.0.clone());
         }
         keys
     }
@@ -1,5 +1,3 @@
-use std::collections::HashMap;
-
 pub mod lfmap;
 
 pub trait KeyValueStorage {
@@ -8,13 +6,3 @@ pub trait KeyValueStorage {
     fn delete(&self, key: &[u8]);
     fn keys(&self) -> Vec<Vec<u8>>;
 }
-
-pub struct StableStorage {
-    stable_index: HashMap<Vec<u8>, usize>,
-    stable_storage: Vec<StableStorageEntry>,
-}
-
-pub struct StableStorageEntry {
-    pub key: Vec<u8>,
-    pub value: Vec<u8>,
-}
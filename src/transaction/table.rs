@@ -1,12 +1,44 @@
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use skiplist::OrderedSkipList;
 
-use crate::types::Xid;
+use crate::types::{self, Timestamp, Xid};
+use crate::util::sync::{AtomicU64, Mutex, Ordering};
 
 pub type TransactionTableRef = Arc<TransactionTable>;
 
+/// Where a transaction begun with `DB::begin` is at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransactionState {
+    /// Reading and buffering writes
+    Active,
+    /// Being committed, it can no longer be aborted
+    Committing,
+    /// Aborted for running past its timeout. Its writes are dropped and
+    /// anything else it does fails with `ErrorKind::Aborted`.
+    Aborted,
+}
+
+/// What the transaction table knows about a transaction, see
+/// `DB::transactions`
+#[derive(Clone, Debug)]
+pub struct TransactionInfo {
+    pub xid: Xid,
+    /// When it began
+    pub started: Timestamp,
+    /// How long it may run before it's aborted, None for as long as it
+    /// likes
+    pub timeout: Option<Duration>,
+    pub state: TransactionState,
+}
+
+struct Tracked {
+    info: TransactionInfo,
+    started_at: Instant,
+}
+
 /// Keeps track of currently active transactions.
 ///
 /// It generates a monotonically increasing transaction id (xid) during 
@@ -17,6 +49,8 @@ pub struct TransactionTable {
     next_xid: AtomicU64,
     // TODO: Maybe RwLock is better?????
     active_xids: Mutex<OrderedSkipList<Xid>>,
+    // Transactions begun with begin_tracked, until they are forgotten
+    tracked: Mutex<HashMap<Xid, Tracked>>,
 }
 
 impl TransactionTable {
@@ -25,17 +59,105 @@ impl TransactionTable {
         Self { 
             next_xid: AtomicU64::new(1),
             active_xids: Mutex::new(OrderedSkipList::new()),
+            tracked: Mutex::new(HashMap::new()),
         }
     }
     
     /// Start a new transaction, get the new transaction id
     pub fn begin(&self) -> Xid {
-        let xid = self.next_xid.fetch_add(1, Ordering::Relaxed);
+        // The xid is taken under the lock so that it's active as soon as
+        // next_xid has moved past it. Otherwise oldest_xid could pass an xid
+        // that is about to become active, and go back down when it does.
         let mut active_xids = self.active_xids.lock().unwrap();
+        let xid = self.next_xid.fetch_add(1, Ordering::Relaxed);
         active_xids.insert(xid);
         xid
     }
     
+    /// Start a transaction whose state and timeout are kept track of until
+    /// it's forgotten
+    pub fn begin_tracked(&self, timeout: Option<Duration>) -> Xid {
+        let xid = self.begin();
+        let info = TransactionInfo {
+            xid,
+            started: types::now(),
+            timeout,
+            state: TransactionState::Active,
+        };
+        let tracked = Tracked { info, started_at: Instant::now() };
+        self.tracked.lock().unwrap().insert(xid, tracked);
+        xid
+    }
+    
+    pub fn set_timeout(&self, xid: Xid, timeout: Option<Duration>) {
+        if let Some(tracked) = self.tracked.lock().unwrap().get_mut(&xid) {
+            tracked.info.timeout = timeout;
+        }
+    }
+    
+    pub fn info(&self, xid: Xid) -> Option<TransactionInfo> {
+        let tracked = self.tracked.lock().unwrap();
+        tracked.get(&xid).map(|tracked| tracked.info.clone())
+    }
+    
+    /// Every tracked transaction, by xid
+    pub fn tracked(&self) -> Vec<TransactionInfo> {
+        let tracked = self.tracked.lock().unwrap();
+        let mut infos: Vec<TransactionInfo> = tracked.values()
+            .map(|tracked| tracked.info.clone())
+            .collect();
+        infos.sort_by_key(|info| info.xid);
+        infos
+    }
+    
+    /// Move an active transaction to Committing, false if it was aborted
+    pub fn start_commit(&self, xid: Xid) -> bool {
+        let mut tracked = self.tracked.lock().unwrap();
+        match tracked.get_mut(&xid) {
+            Some(tracked) if tracked.info.state == TransactionState::Active => {
+                tracked.info.state = TransactionState::Committing;
+                true
+            }
+            _ => false,
+        }
+    }
+    
+    /// Abort the active transactions that ran past their timeout as of
+    /// now. They are no longer active, but stay tracked as Aborted.
+    pub fn abort_expired(&self, now: Instant) -> Vec<Xid> {
+        let mut aborted = vec![];
+        {
+            let mut tracked = self.tracked.lock().unwrap();
+            for (xid, tracked) in tracked.iter_mut() {
+                let expired = tracked.info.timeout.is_some_and(|timeout| {
+                    now.duration_since(tracked.started_at) >= timeout
+                });
+                if tracked.info.state == TransactionState::Active && expired {
+                    tracked.info.state = TransactionState::Aborted;
+                    aborted.push(*xid);
+                }
+            }
+        }
+        // Marked Aborted first, so that a transaction seeing itself Active
+        // after reading knows it was still active while it read
+        for xid in &aborted {
+            self.end(xid);
+        }
+        aborted
+    }
+    
+    /// End a tracked transaction and stop tracking it
+    pub fn forget(&self, xid: Xid) {
+        self.tracked.lock().unwrap().remove(&xid);
+        self.end(&xid);
+    }
+    
+    /// Take the next xid without the transaction becoming active, to order
+    /// something with respect to the transactions that begin
+    pub fn allocate(&self) -> Xid {
+        self.next_xid.fetch_add(1, Ordering::Relaxed)
+    }
+    
     /// Mark a transaction given by xid as completed.
     pub fn end(&self, xid: &Xid) {
         let mut active_xids = self.active_xids.lock().unwrap();
@@ -43,13 +165,87 @@ impl TransactionTable {
     }
     
     pub fn next_xid(&self) -> Xid {
-        let next_xid = self.next_xid.load(Ordering::Relaxed);
-        next_xid
+        self.next_xid.load(Ordering::Relaxed)
     }
     
     /// Returns the oldest transaction id that is still active.
     pub fn oldest_xid(&self) -> Option<u64> {
         let active_xids = self.active_xids.lock().unwrap();
-        active_xids.front().map(|x| x.clone())
+        active_xids.front().copied()
+    }
+    
+    /// Returns the oldest transaction id that is still active, leaving out
+    /// the tracked transactions that haven't started to commit. Those
+    /// haven't read the checkpoint phase, they commit in whatever phase the
+    /// database is in when they do.
+    pub fn oldest_committing_xid(&self) -> Option<Xid> {
+        let tracked = self.tracked.lock().unwrap();
+        let active_xids = self.active_xids.lock().unwrap();
+        active_xids.iter()
+            .find(|xid| {
+                tracked.get(xid).is_none_or(|tracked| {
+                    tracked.info.state != TransactionState::Active
+                })
+            })
+            .copied()
+    }
+    
+    /// Number of transactions still active
+    pub fn active_count(&self) -> usize {
+        self.active_xids.lock().unwrap().len()
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+    
+    // Checkpoints wait for oldest_xid to pass what next_xid was when the
+    // phase changed. Once it has, no transaction with a smaller xid may
+    // become active, and oldest_xid never goes back down.
+    #[test]
+    fn loom_oldest_xid_passes_next_xid() {
+        // Three threads take long to check exhaustively, the races here
+        // show with few preemptions
+        let mut model = loom::model::Builder::new();
+        model.preemption_bound = Some(3);
+        model.check(|| {
+            let xtable = Arc::new(TransactionTable::new());
+            let passed = Arc::new(Mutex::new(None));
+            let workers: Vec<_> = (0..2)
+                .map(|_| {
+                    let (xtable, passed) = (xtable.clone(), passed.clone());
+                    thread::spawn(move || {
+                        let xid = xtable.begin();
+                        if let Some(next_xid) = *passed.lock().unwrap() {
+                            assert!(xid >= next_xid);
+                        }
+                        xtable.end(&xid);
+                    })
+                })
+                .collect();
+            
+            let next_xid = xtable.next_xid();
+            let first = xtable.oldest_xid();
+            let has_passed = match first {
+                Some(oldest) => oldest >= next_xid,
+                None => true,
+            };
+            if has_passed {
+                *passed.lock().unwrap() = Some(next_xid);
+            }
+            let second = xtable.oldest_xid();
+            if let (Some(first), Some(second)) = (first, second) {
+                assert!(second >= first);
+            }
+            
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            assert_eq!(xtable.oldest_xid(), None);
+            assert_eq!(xtable.next_xid(), 3);
+        });
     }
 }
@@ -1,13 +1,74 @@
 use std::convert::TryFrom;
+use std::fmt;
+use std::io::Cursor;
+
+use byteorder::ReadBytesExt;
+
+use crate::util::serde::{Deserialize, FormatVersion, Serialize};
 
 // Transaction ID types
 pub type Xid = u64;
 
+// Log sequence number, the byte offset into the WAL
+pub type Lsn = u64;
+
+// Wall-clock time in microseconds since the Unix epoch
+pub type Timestamp = u64;
+
+/// The current time as a Timestamp
+pub fn now() -> Timestamp {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as Timestamp)
+}
+
+/// What went wrong, for the errors a caller may want to handle
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    Other,
+    /// A transaction conflicted with another one and was rolled back, it
+    /// may succeed if retried
+    Conflict,
+    /// A transaction was aborted for running past its timeout
+    Aborted,
+    /// A log entry runs past the end of the file
+    Incomplete,
+}
+
 #[derive(Debug)]
 pub struct Error {
+    kind: ErrorKind,
     message: String,
 }
 
+impl Error {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        Self::with_kind(ErrorKind::Other, message)
+    }
+    
+    pub fn with_kind<S: Into<String>>(kind: ErrorKind, message: S) -> Self {
+        Self { kind, message: message.into() }
+    }
+    
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::new(err.to_string())
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CheckpointPhase {
     REST = 1,
@@ -30,6 +91,7 @@ impl TryFrom<u8> for CheckpointPhase {
             _ => {
                 Err(
                     Self::Error { 
+                        kind: ErrorKind::Other,
                         message: format!("Unknown checkpoint phase: {}", v) 
                     }
                 )
@@ -37,3 +99,42 @@ impl TryFrom<u8> for CheckpointPhase {
         }
     }
 }
+
+impl Serialize for CheckpointPhase {
+    fn serialize_into(&self, res: &mut Vec<u8>) {
+        res.push(*self as u8);
+    }
+}
+
+impl<'a> Deserialize<'a> for CheckpointPhase {
+    fn deserialize(rdr: &mut Cursor<&'a [u8]>, _version: FormatVersion)
+        -> Result<Self, Error>
+    {
+        Self::try_from(rdr.read_u8()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::serde::{self, CURRENT_VERSION};
+    use proptest::prelude::*;
+    
+    proptest! {
+        #[test]
+        fn checkpoint_phase_round_trip(v in any::<u8>()) {
+            // Every byte either decodes to a phase that encodes back to it
+            // or is rejected
+            let bytes = [v];
+            let phase: Result<CheckpointPhase, _> =
+                serde::from_bytes(&bytes, CURRENT_VERSION);
+            match phase {
+                Ok(phase) => prop_assert_eq!(phase.serialize(), vec![v]),
+                Err(_) => {
+                    let last = CheckpointPhase::COMPLETE as u8;
+                    prop_assert!(v == 0 || v > last);
+                }
+            }
+        }
+    }
+}
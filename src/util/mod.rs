@@ -1 +1,2 @@
-pub mod serde;
\ No newline at end of file
+pub mod serde;
+pub(crate) mod sync;
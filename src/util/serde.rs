@@ -1,76 +1,214 @@
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ReadBytesExt};
 
-use crate::types::Xid;
+use crate::types::{Error, Xid};
 
+/// Encoding of a type that is persisted, in the WAL or in a checkpoint.
+///
+/// Values are always written in CURRENT_VERSION.
 pub trait Serialize {
-    fn serialize(&self) -> Vec<u8>;
+    /// Append the serialized form to res
+    fn serialize_into(&self, res: &mut Vec<u8>);
+    
+    fn serialize(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        self.serialize_into(&mut res);
+        res
+    }
+}
+
+/// Decoding counterpart of Serialize.
+///
+/// Reads one value from rdr, written in the given format version, and
+/// leaves rdr just past it. The value may borrow from the buffer rdr reads.
+/// Truncated or malformed input is an error, never a panic, since it can
+/// come from a torn write or a corrupted file.
+pub trait Deserialize<'a>: Sized {
+    fn deserialize(rdr: &mut Cursor<&'a [u8]>, version: FormatVersion)
+        -> Result<Self, Error>;
+}
+
+/// Decode a value that must take up all of bytes
+pub fn from_bytes<'a, T: Deserialize<'a>>(
+    bytes: &'a [u8],
+    version: FormatVersion,
+) -> Result<T, Error> {
+    let mut rdr = Cursor::new(bytes);
+    let res = T::deserialize(&mut rdr, version)?;
+    if rdr.position() != bytes.len() as u64 {
+        return Err(Error::new(format!(
+            "{} trailing bytes after value",
+            bytes.len() as u64 - rdr.position()
+        )));
+    }
+    Ok(res)
 }
 
-pub trait Deserialize {
-    fn deserialize(&mut self, rdr: &mut Cursor<Vec<u8>>);
+/// Versions of the on-disk encoding.
+///
+/// Everything is written in the current version, older versions are only
+/// read so that files written by earlier releases still load.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FormatVersion {
+    /// Lengths are native usize, 8, 4 or 2 bytes depending on
+    /// target_pointer_width, so files aren't portable between hosts.
+    V1 = 1,
+    /// Lengths are LEB128 varints, xids are fixed 8-byte integers.
+    V2 = 2,
+    /// Same as V2, XCommit log entries carry the commit timestamp.
+    V3 = 3,
 }
 
+pub const CURRENT_VERSION: FormatVersion = FormatVersion::V3;
+
+impl FormatVersion {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Self::V1),
+            2 => Some(Self::V2),
+            3 => Some(Self::V3),
+            _ => None,
+        }
+    }
+}
+
+/// Size of a V1 length
+pub const LEGACY_USIZE_LEN: usize = std::mem::size_of::<usize>();
+
+/// Longest encoding of a u64 varint
+pub const MAX_VARINT_LEN: usize = 10;
+
 pub fn serialize_u8_vec(res: &mut Vec<u8>, data: &[u8]) {
     let content_size = data.len();
     serialize_usize(res, content_size);
-    for d in data {
-        res.write_u8(*d);
-    }
+    res.extend_from_slice(data);
+}
+
+pub fn deserialize_u8_vec(
+    rdr: &mut Cursor<&[u8]>,
+    version: FormatVersion,
+) -> Result<Vec<u8>, Error> {
+    Ok(deserialize_u8_slice(rdr, version)?.to_vec())
 }
 
-pub fn deserialize_u8_vec(rdr: &mut Cursor<&[u8]>) -> Vec<u8> {
-    let mut res = Vec::new();
-    let size = deserialize_usize(rdr);
-    for _ in 0..size {
-        let b = rdr.read_u8().unwrap();
-        res.push(b);
+/// Like deserialize_u8_vec, but borrows the bytes from the buffer rdr is
+/// reading instead of copying them.
+pub fn deserialize_u8_slice<'a>(
+    rdr: &mut Cursor<&'a [u8]>,
+    version: FormatVersion,
+) -> Result<&'a [u8], Error> {
+    let size = match version {
+        FormatVersion::V1 => deserialize_legacy_usize(rdr)?,
+        FormatVersion::V2 | FormatVersion::V3 => deserialize_usize(rdr)?,
+    };
+    let buf: &'a [u8] = rdr.get_ref();
+    let start = rdr.position() as usize;
+    if size > buf.len().saturating_sub(start) {
+        return Err(Error::new(format!(
+            "Byte string of length {} runs past the end of the buffer",
+            size
+        )));
     }
-    res
+    let end = start + size;
+    rdr.set_position(end as u64);
+    Ok(&buf[start..end])
 }
 
 pub fn serialize_usize(res: &mut Vec<u8>, size: usize) {
-    if cfg!(target_pointer_width = "64") {
-        let size = size as u64;
-        res.write_u64::<BigEndian>(size).unwrap();
-    } else if cfg!(target_pointer_width = "32") {
-        let size = size as u32;
-        res.write_u32::<BigEndian>(size).unwrap();
-    } else {
-        let size = size as u16;
-        res.write_u16::<BigEndian>(size).unwrap();
-    }
+    serialize_varint(res, size as u64);
 }
 
-pub fn deserialize_usize(rdr: &mut Cursor<&[u8]>) -> usize {
+pub fn deserialize_usize(rdr: &mut Cursor<&[u8]>) -> Result<usize, Error> {
+    Ok(read_varint(rdr)? as usize)
+}
+
+/// Read a length written by the V1 encoding
+pub fn deserialize_legacy_usize(rdr: &mut Cursor<&[u8]>)
+    -> Result<usize, Error>
+{
     if cfg!(target_pointer_width = "64") {
-        let size = rdr.read_u64::<BigEndian>().unwrap();
+        let size = rdr.read_u64::<BigEndian>()?;
         let size = size as usize;
-        size
+        Ok(size)
     } else if cfg!(target_pointer_width = "32") {
-        let size = rdr.read_u32::<BigEndian>().unwrap();
+        let size = rdr.read_u32::<BigEndian>()?;
         let size = size as usize;
-        size
+        Ok(size)
     } else {
-        let size = rdr.read_u16::<BigEndian>().unwrap();
+        let size = rdr.read_u16::<BigEndian>()?;
         let size = size as usize;
-        size
+        Ok(size)
     }
 }
 
+/// Write v as an unsigned LEB128 varint: 7 bits per byte, least significant
+/// group first, high bit set on every byte but the last.
+pub fn serialize_varint(res: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        res.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    res.push(v as u8);
+}
+
+/// Like serialize_varint, but writes into buf, which must be at least
+/// MAX_VARINT_LEN long. Returns the number of bytes written.
+pub fn encode_varint(buf: &mut [u8], mut v: u64) -> usize {
+    let mut i = 0;
+    while v >= 0x80 {
+        buf[i] = (v as u8) | 0x80;
+        v >>= 7;
+        i += 1;
+    }
+    buf[i] = v as u8;
+    i + 1
+}
+
+/// Number of bytes serialize_varint uses for v
+pub fn varint_len(mut v: u64) -> usize {
+    let mut len = 1;
+    while v >= 0x80 {
+        v >>= 7;
+        len += 1;
+    }
+    len
+}
+
+/// Read an unsigned LEB128 varint from any reader
+pub fn read_varint<R: Read>(rdr: &mut R) -> std::io::Result<u64> {
+    let mut v: u64 = 0;
+    for i in 0..MAX_VARINT_LEN {
+        let b = rdr.read_u8()?;
+        v |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "varint is too long",
+    ))
+}
+
+pub fn serialize_u64(res: &mut Vec<u8>, v: u64) {
+    res.extend_from_slice(&v.to_be_bytes());
+}
+
 pub fn serialize_xid(res: &mut Vec<u8>, xid: &Xid) {
-    res.write_u64::<BigEndian>(*xid).unwrap();
+    serialize_u64(res, *xid);
 }
 
-pub fn deserialize_xid(rdr: &mut Cursor<&[u8]>) -> Xid {
-    let xid = rdr.read_u64::<BigEndian>().unwrap();
-    xid
+pub fn deserialize_xid(rdr: &mut Cursor<&[u8]>) -> Result<Xid, Error> {
+    let xid = rdr.read_u64::<BigEndian>()?;
+    Ok(xid)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
     
     #[test]
     fn serde_u8_vec() {
@@ -78,8 +216,91 @@ mod tests {
         // Serialize
         let mut res = Vec::new();
         serialize_u8_vec(&mut res, &v);
+        assert_eq!(res.len(), 4);
         // Deserialize
-        let v_out = deserialize_u8_vec(&mut Cursor::new(&res));
+        let v_out = deserialize_u8_vec(
+            &mut Cursor::new(&res),
+            CURRENT_VERSION,
+        ).unwrap();
         assert_eq!(v_out, v);
     }
+    
+    #[test]
+    fn deserialize_borrowed() {
+        let mut res = Vec::new();
+        serialize_u8_vec(&mut res, b"foo");
+        serialize_u8_vec(&mut res, b"");
+        serialize_u8_vec(&mut res, b"barbaz");
+        let mut rdr = Cursor::new(&res[..]);
+        let foo = deserialize_u8_slice(&mut rdr, CURRENT_VERSION).unwrap();
+        let empty = deserialize_u8_slice(&mut rdr, CURRENT_VERSION).unwrap();
+        let barbaz = deserialize_u8_slice(&mut rdr, CURRENT_VERSION).unwrap();
+        assert_eq!(
+            (foo, empty, barbaz),
+            (&b"foo"[..], &b""[..], &b"barbaz"[..])
+        );
+        // Points into res, nothing was copied
+        assert_eq!(barbaz.as_ptr(), res[res.len() - 6..].as_ptr());
+    }
+    
+    #[test]
+    fn serde_legacy_u8_vec() {
+        let mut res = Vec::new();
+        res.extend_from_slice(&3usize.to_be_bytes());
+        res.extend_from_slice(&[1, 2, 3]);
+        let v_out = deserialize_u8_vec(
+            &mut Cursor::new(&res),
+            FormatVersion::V1,
+        ).unwrap();
+        assert_eq!(v_out, vec!(1, 2, 3));
+    }
+    
+    #[test]
+    fn serde_varint() {
+        let values = [0, 1, 127, 128, 300, 16383, 16384, u64::MAX];
+        for v in &values {
+            let mut res = Vec::new();
+            serialize_varint(&mut res, *v);
+            let len = res.len();
+            let mut rdr = Cursor::new(&res[..]);
+            assert_eq!(read_varint(&mut rdr).unwrap(), *v);
+            assert_eq!(rdr.position() as usize, len);
+            let mut buf = [0u8; MAX_VARINT_LEN];
+            assert_eq!(encode_varint(&mut buf, *v), len);
+            assert_eq!(&buf[..len], &res[..]);
+        }
+        let mut res = Vec::new();
+        serialize_varint(&mut res, u64::MAX);
+        assert_eq!(res.len(), MAX_VARINT_LEN);
+    }
+    
+    #[test]
+    fn truncated_u8_vec() {
+        let mut res = Vec::new();
+        serialize_u8_vec(&mut res, b"foo");
+        for len in 0..res.len() {
+            let mut rdr = Cursor::new(&res[..len]);
+            assert!(deserialize_u8_slice(&mut rdr, CURRENT_VERSION).is_err());
+        }
+    }
+    
+    proptest! {
+        #[test]
+        fn u8_vec_round_trip(data in vec(any::<u8>(), 0..1024)) {
+            let mut res = Vec::new();
+            serialize_u8_vec(&mut res, &data);
+            let mut rdr = Cursor::new(&res[..]);
+            let data_out = deserialize_u8_vec(&mut rdr, CURRENT_VERSION);
+            prop_assert_eq!(data_out.unwrap(), data);
+            prop_assert_eq!(rdr.position() as usize, res.len());
+        }
+        
+        #[test]
+        fn varint_round_trip(v in any::<u64>()) {
+            let mut res = Vec::new();
+            serialize_varint(&mut res, v);
+            prop_assert_eq!(res.len(), varint_len(v));
+            prop_assert_eq!(read_varint(&mut &res[..]).unwrap(), v);
+        }
+    }
 }
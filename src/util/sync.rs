@@ -0,0 +1,19 @@
+//! Synchronization primitives for the code checked with loom.
+//!
+//! They are loom's when built with `--cfg loom` and std's otherwise. The
+//! models only make sense under loom::model, so run nothing else in that
+//! build:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --lib loom
+//! ```
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicU64, Ordering};
+#[cfg(loom)]
+pub(crate) use loom::sync::{Mutex, RwLock, RwLockReadGuard};
+
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(not(loom))]
+pub(crate) use std::sync::{Mutex, RwLock, RwLockReadGuard};